@@ -0,0 +1,154 @@
+use candid::{CandidType, Deserialize};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use serde::Serialize;
+
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 5;
+pub const MAX_ATTACHMENT_SIZE: usize = 1_500_000; // bytes of encoded data
+// Ingress messages, and WebSocket messages relayed by the gateway as update calls, are capped at 2MiB,
+// this leaves room for the rest of the call and its candid encoding
+pub const MAX_ATTACHMENTS_TOTAL_SIZE: usize = 1_800_000;
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+const IMAGE_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/webp", "image/gif"];
+const FILE_TYPES: [&str; 6] = [
+    "application/pdf",
+    "text/plain",
+    "text/csv",
+    "application/zip",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+];
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum AttachmentKind {
+    Image,
+    File,
+}
+
+/// Attachment metadata stored on the message, the data is fetched with `get_chat_attachment`.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Attachment {
+    pub id: u64, // id in the image storage shared with listings
+    pub kind: AttachmentKind,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64, // length of the encoded `data` string as uploaded, not of the decoded file
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct AttachmentInput {
+    pub kind: AttachmentKind,
+    pub file_name: String,
+    pub mime_type: String,
+    pub data: String, // same format as listing images
+}
+
+thread_local! {
+    // image id -> conversation the attachment was sent in
+    pub static ATTACHMENTS: RefCell<HashMap<u64, String>> = RefCell::new(HashMap::new());
+}
+
+impl AttachmentInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.file_name.trim().is_empty() || self.file_name.len() > MAX_FILE_NAME_LEN {
+            return Err(format!("File name must have between 1 and {} characters", MAX_FILE_NAME_LEN));
+        }
+        if self.data.is_empty() {
+            return Err(format!("Attachment {} is empty", self.file_name));
+        }
+        if self.data.len() > MAX_ATTACHMENT_SIZE {
+            return Err(format!("Attachment {} is larger than {} bytes", self.file_name, MAX_ATTACHMENT_SIZE));
+        }
+
+        let allowed: &[&str] = match self.kind {
+            AttachmentKind::Image => &IMAGE_TYPES,
+            AttachmentKind::File => &FILE_TYPES,
+        };
+        if !allowed.contains(&self.mime_type.as_str()) {
+            return Err(format!("Attachments of type {} are not allowed", self.mime_type));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn validate_attachments(attachments: &[AttachmentInput]) -> Result<(), String> {
+    if attachments.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(format!("A message can have at most {} attachments", MAX_ATTACHMENTS_PER_MESSAGE));
+    }
+    attachments.iter().try_for_each(|attachment| attachment.validate())?;
+
+    let total: usize = attachments.iter().map(|attachment| attachment.data.len()).sum();
+    if total > MAX_ATTACHMENTS_TOTAL_SIZE {
+        return Err(format!("Attachments of a message cannot exceed {} bytes in total", MAX_ATTACHMENTS_TOTAL_SIZE));
+    }
+    Ok(())
+}
+
+/// Stores validated attachments and remembers which conversation may read them.
+pub fn store_attachments(conversation_id: &str, attachments: Vec<AttachmentInput>) -> Vec<Attachment> {
+    let data: Vec<String> = attachments.iter().map(|attachment| attachment.data.clone()).collect();
+    let ids = crate::store_images(&data);
+
+    ATTACHMENTS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for id in &ids {
+            stored.insert(*id, conversation_id.to_string());
+        }
+    });
+
+    attachments
+        .into_iter()
+        .zip(ids)
+        .map(|(attachment, id)| Attachment {
+            id,
+            kind: attachment.kind,
+            file_name: attachment.file_name.trim().to_string(),
+            mime_type: attachment.mime_type,
+            size: attachment.data.len() as u64,
+        })
+        .collect()
+}
+
+pub fn release_attachments(attachments: &[Attachment]) {
+    let ids: Vec<u64> = attachments.iter().map(|attachment| attachment.id).collect();
+
+    ATTACHMENTS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for id in &ids {
+            stored.remove(id);
+        }
+    });
+    crate::release_images(&ids);
+}
+
+/// Conversation the stored image belongs to, None for listing images.
+pub fn attachment_conversation(image_id: u64) -> Option<String> {
+    ATTACHMENTS.with(|stored| stored.borrow().get(&image_id).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: usize) -> AttachmentInput {
+        AttachmentInput {
+            kind: AttachmentKind::Image,
+            file_name: "photo.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: "a".repeat(size),
+        }
+    }
+
+    #[test]
+    fn total_size_is_limited() {
+        assert!(validate_attachments(&[image(MAX_ATTACHMENT_SIZE)]).is_ok());
+        assert!(validate_attachments(&[image(MAX_ATTACHMENT_SIZE + 1)]).is_err());
+        assert!(validate_attachments(&[image(MAX_ATTACHMENT_SIZE), image(MAX_ATTACHMENT_SIZE)]).is_err());
+
+        let half = MAX_ATTACHMENTS_TOTAL_SIZE / 2;
+        assert!(validate_attachments(&[image(half), image(half)]).is_ok());
+        assert!(validate_attachments(&[image(half), image(half + 1)]).is_err());
+    }
+}
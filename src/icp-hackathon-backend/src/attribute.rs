@@ -0,0 +1,310 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::fmt;
+
+pub const MAX_TEXT_VALUE_LEN: usize = 200;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum AttributeType {
+    Text,
+    Number,
+    Boolean,
+    Choice(Vec<String>),
+    Ean, // EAN-8, UPC-A, EAN-13 or GTIN-14 with a valid check digit
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AttributeDefinition {
+    pub key: String,
+    pub label: String,
+    pub value_type: AttributeType,
+    pub unit: Option<String>, // e.g. "kg", shown next to number values
+    pub required: bool,
+}
+
+/// Attributes available to listings in `categories_path` and its subcategories, "" applies to every category.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AttributeSchema {
+    pub categories_path: String,
+    pub attributes: Vec<AttributeDefinition>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    Number(f64),
+    Boolean(bool),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct ListingAttribute {
+    pub key: String,
+    pub value: AttributeValue,
+}
+
+/// Matches listings whose attribute equals `equals` and, for numbers, lies within `min`..=`max`.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AttributeFilter {
+    pub key: String,
+    pub equals: Option<AttributeValue>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl AttributeDefinition {
+    pub fn new(key: &str, label: &str, value_type: AttributeType, unit: Option<&str>) -> Self {
+        Self {
+            key: key.to_string(),
+            label: label.to_string(),
+            value_type,
+            unit: unit.map(|unit| unit.to_string()),
+            required: false,
+        }
+    }
+
+    fn check_value(&self, value: AttributeValue) -> Result<AttributeValue, String> {
+        match (&self.value_type, value) {
+            (AttributeType::Text, AttributeValue::Text(text)) => {
+                let text = text.trim().to_string();
+                if text.is_empty() || text.len() > MAX_TEXT_VALUE_LEN {
+                    return Err(format!("Attribute {} must have between 1 and {} characters.", self.key, MAX_TEXT_VALUE_LEN));
+                }
+                Ok(AttributeValue::Text(text))
+            }
+            (AttributeType::Number, AttributeValue::Number(number)) => {
+                if !number.is_finite() {
+                    return Err(format!("Attribute {} must be a finite number.", self.key));
+                }
+                Ok(AttributeValue::Number(number))
+            }
+            (AttributeType::Boolean, AttributeValue::Boolean(flag)) => Ok(AttributeValue::Boolean(flag)),
+            (AttributeType::Choice(options), AttributeValue::Text(text)) => {
+                // Stored with the schema spelling so filters can compare exact values
+                match options.iter().find(|option| option.eq_ignore_ascii_case(text.trim())) {
+                    Some(option) => Ok(AttributeValue::Text(option.clone())),
+                    None => Err(format!("Attribute {} must be one of: {}.", self.key, options.join(", "))),
+                }
+            }
+            (AttributeType::Ean, AttributeValue::Text(text)) => {
+                let code = text.trim().to_string();
+                if !is_valid_ean(&code) {
+                    return Err(format!("Attribute {} is not a valid EAN/GTIN code.", self.key));
+                }
+                Ok(AttributeValue::Text(code))
+            }
+            (value_type, _) => Err(format!("Attribute {} must be of type {:?}.", self.key, value_type)),
+        }
+    }
+}
+
+impl AttributeSchema {
+    pub fn new(categories_path: &str, attributes: Vec<AttributeDefinition>) -> Self {
+        Self {
+            categories_path: categories_path.to_string(),
+            attributes,
+        }
+    }
+
+    pub fn applies_to(&self, categories_path: &str) -> bool {
+        self.categories_path.is_empty()
+            || categories_path == self.categories_path
+            || categories_path.starts_with(&format!("{}/", self.categories_path))
+    }
+}
+
+impl fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttributeValue::Text(text) => write!(f, "{}", text),
+            AttributeValue::Number(number) => write!(f, "{}", number),
+            AttributeValue::Boolean(flag) => write!(f, "{}", if *flag { "yes" } else { "no" }),
+        }
+    }
+}
+
+impl AttributeFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(format!("Minimum value of {} cannot be greater than its maximum.", self.key));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, attributes: &[ListingAttribute]) -> bool {
+        let value = match attributes.iter().find(|attribute| attribute.key == self.key) {
+            Some(attribute) => &attribute.value,
+            None => return false,
+        };
+
+        let equals = self.equals.as_ref().is_none_or(|expected| match (expected, value) {
+            (AttributeValue::Text(expected), AttributeValue::Text(actual)) => expected.trim().eq_ignore_ascii_case(actual),
+            (expected, actual) => expected == actual,
+        });
+        let in_range = match value {
+            AttributeValue::Number(number) => {
+                self.min.is_none_or(|min| *number >= min) && self.max.is_none_or(|max| *number <= max)
+            }
+            _ => self.min.is_none() && self.max.is_none(),
+        };
+
+        equals && in_range
+    }
+}
+
+/// Definitions of every schema matching the category, more specific schemas override keys of general ones.
+pub fn definitions_for(schemas: &[AttributeSchema], categories_path: &str) -> Vec<AttributeDefinition> {
+    let mut matching: Vec<&AttributeSchema> = schemas.iter().filter(|schema| schema.applies_to(categories_path)).collect();
+    matching.sort_by_key(|schema| schema.categories_path.len());
+
+    let mut definitions: Vec<AttributeDefinition> = Vec::new();
+    for definition in matching.into_iter().flat_map(|schema| schema.attributes.iter()) {
+        match definitions.iter_mut().find(|existing| existing.key == definition.key) {
+            Some(existing) => *existing = definition.clone(),
+            None => definitions.push(definition.clone()),
+        }
+    }
+    definitions
+}
+
+/// Checks the attributes against the definitions, returns them normalized and in schema order.
+pub fn validate_attributes(definitions: &[AttributeDefinition], attributes: Vec<ListingAttribute>) -> Result<Vec<ListingAttribute>, String> {
+    let mut checked: Vec<ListingAttribute> = Vec::new();
+
+    for attribute in attributes {
+        let definition = match definitions.iter().find(|definition| definition.key == attribute.key) {
+            Some(definition) => definition,
+            None => return Err(format!("Attribute {} is not available in this category.", attribute.key)),
+        };
+        if checked.iter().any(|existing| existing.key == attribute.key) {
+            return Err(format!("Attribute {} is set more than once.", attribute.key));
+        }
+
+        checked.push(ListingAttribute {
+            key: attribute.key,
+            value: definition.check_value(attribute.value)?,
+        });
+    }
+
+    if let Some(missing) = definitions.iter().find(|definition| definition.required && !checked.iter().any(|attribute| attribute.key == definition.key)) {
+        return Err(format!("Attribute {} is required in this category.", missing.key));
+    }
+
+    checked.sort_by_key(|attribute| definitions.iter().position(|definition| definition.key == attribute.key));
+    Ok(checked)
+}
+
+pub fn format_attributes(attributes: &[ListingAttribute]) -> String {
+    attributes
+        .iter()
+        .map(|attribute| format!("{}={}", attribute.key, attribute.value))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+// GS1 check digit: digits are weighted 3 and 1 alternately, starting from the right
+fn is_valid_ean(code: &str) -> bool {
+    if ![8, 12, 13, 14].contains(&code.len()) || !code.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+
+    let digits: Vec<u32> = code.chars().filter_map(|c| c.to_digit(10)).collect();
+    let (check_digit, payload) = digits.split_last().unwrap();
+    let sum: u32 = payload
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, digit)| if i % 2 == 0 { digit * 3 } else { *digit })
+        .sum();
+
+    (10 - sum % 10) % 10 == *check_digit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(key: &str, value: AttributeValue) -> ListingAttribute {
+        ListingAttribute { key: key.to_string(), value }
+    }
+
+    fn filter(key: &str, equals: Option<AttributeValue>, min: Option<f64>, max: Option<f64>) -> AttributeFilter {
+        AttributeFilter { key: key.to_string(), equals, min, max }
+    }
+
+    fn bricks() -> Vec<ListingAttribute> {
+        vec![
+            attribute("color", AttributeValue::Text("Red".to_string())),
+            attribute("weight", AttributeValue::Number(2.5)),
+            attribute("frost_resistant", AttributeValue::Boolean(true)),
+        ]
+    }
+
+    #[test]
+    fn filters_match_values_and_number_ranges() {
+        let attributes = bricks();
+
+        assert!(filter("color", Some(AttributeValue::Text(" red ".to_string())), None, None).matches(&attributes));
+        assert!(!filter("color", Some(AttributeValue::Text("yellow".to_string())), None, None).matches(&attributes));
+        assert!(filter("frost_resistant", Some(AttributeValue::Boolean(true)), None, None).matches(&attributes));
+        assert!(filter("weight", None, Some(2.5), Some(2.5)).matches(&attributes));
+        assert!(filter("weight", None, Some(1.0), None).matches(&attributes));
+        assert!(!filter("weight", None, None, Some(2.0)).matches(&attributes));
+        // Ranges only apply to numbers, and listings without the attribute never match
+        assert!(!filter("color", None, Some(0.0), None).matches(&attributes));
+        assert!(!filter("size", None, None, None).matches(&attributes));
+    }
+
+    #[test]
+    fn filters_with_inverted_ranges_are_rejected() {
+        assert!(filter("weight", None, Some(3.0), Some(2.0)).validate().is_err());
+        assert!(filter("weight", None, Some(2.0), Some(2.0)).validate().is_ok());
+    }
+
+    #[test]
+    fn attributes_are_checked_and_normalized_against_the_definitions() {
+        let mut ean = AttributeDefinition::new("ean", "EAN", AttributeType::Ean, None);
+        ean.required = true;
+        let definitions = vec![
+            AttributeDefinition::new("color", "Color", AttributeType::Choice(vec!["Red".to_string(), "Yellow".to_string()]), None),
+            AttributeDefinition::new("weight", "Weight", AttributeType::Number, Some("kg")),
+            ean,
+        ];
+
+        let checked = validate_attributes(
+            &definitions,
+            vec![
+                attribute("ean", AttributeValue::Text(" 4006381333931 ".to_string())),
+                attribute("color", AttributeValue::Text("red".to_string())),
+            ],
+        )
+        .unwrap();
+        assert_eq!(format_attributes(&checked), "color=Red; ean=4006381333931");
+
+        let invalid = [
+            vec![attribute("ean", AttributeValue::Text("4006381333932".to_string()))],
+            vec![attribute("color", AttributeValue::Text("red".to_string()))],
+            vec![attribute("ean", AttributeValue::Text("4006381333931".to_string())), attribute("size", AttributeValue::Number(1.0))],
+            vec![attribute("ean", AttributeValue::Text("4006381333931".to_string())), attribute("weight", AttributeValue::Text("heavy".to_string()))],
+            vec![attribute("ean", AttributeValue::Text("4006381333931".to_string())), attribute("ean", AttributeValue::Text("4006381333931".to_string()))],
+        ];
+        for attributes in invalid {
+            assert!(validate_attributes(&definitions, attributes).is_err());
+        }
+    }
+
+    #[test]
+    fn subcategory_schemas_override_general_definitions() {
+        let schemas = vec![
+            AttributeSchema::new("", vec![AttributeDefinition::new("color", "Color", AttributeType::Text, None)]),
+            AttributeSchema::new("Construction/Bricks", vec![AttributeDefinition::new("weight", "Weight", AttributeType::Number, Some("kg"))]),
+            AttributeSchema::new("Construction", vec![AttributeDefinition::new("color", "Colour", AttributeType::Text, None)]),
+        ];
+
+        let definitions = definitions_for(&schemas, "Construction/Bricks/Clay");
+        let labels: Vec<&str> = definitions.iter().map(|definition| definition.label.as_str()).collect();
+        assert_eq!(labels, vec!["Colour", "Weight"]);
+        assert_eq!(definitions_for(&schemas, "Electronics").len(), 1);
+    }
+}
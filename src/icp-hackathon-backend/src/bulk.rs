@@ -0,0 +1,121 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use base64::Engine;
+use crate::config::Config;
+use crate::listing::{Listing, ListingStatus, NewListing};
+use crate::price::{to_decimal, PriceTierInput, WholesalePricing};
+
+/// Outcome of one imported item, `index` is its position in the request.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ImportResult {
+    pub index: u32,
+    pub listing_id: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Exported listing, `listing` can be passed back to `import_listings` as is.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ListingExport {
+    pub id: u64,
+    pub listing: NewListing, // carries the image data, `images_id` are the ids in this canister
+    pub status: ListingStatus,
+    pub images_id: Vec<u64>,
+    pub date: u64,
+    pub expires_at: u64,
+}
+
+impl ListingExport {
+    /// `images` are the stored images of the listing, in the order of `images_id`.
+    pub fn new(listing: &Listing, config: &Config, images: &[String]) -> Self {
+        let decimals = config.find_currency(&listing.price.currency).map_or(0, |currency| currency.decimals);
+
+        Self {
+            id: listing.id,
+            listing: NewListing {
+                title: listing.title.clone(),
+                description: listing.description.clone(),
+                category: listing.category.clone(),
+                price: to_decimal(listing.price.amount, decimals),
+                currency: Some(listing.price.currency.clone()),
+                wholesale_pricing: Some(WholesalePricing {
+                    min_order_quantity: listing.min_order_quantity,
+                    price_tiers: listing
+                        .price_tiers
+                        .iter()
+                        .map(|tier| PriceTierInput {
+                            min_quantity: tier.min_quantity,
+                            unit_price: to_decimal(tier.unit_amount, decimals),
+                        })
+                        .collect(),
+                }),
+                amount: listing.amount,
+                images_strings: images.iter().filter_map(|image| decode_image(image)).collect(),
+                categories_path: listing.categories_path.clone(),
+                attributes: listing.attributes.clone(),
+                draft: listing.status == ListingStatus::Draft,
+            },
+            status: listing.status.clone(),
+            images_id: listing.images_id.clone(),
+            date: listing.date,
+            expires_at: listing.expires_at,
+        }
+    }
+}
+
+// Images are stored base64 encoded, imports take them as originally uploaded
+fn decode_image(image: &str) -> Option<String> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(image).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribute::{AttributeValue, ListingAttribute};
+    use crate::price::{Price, PriceTier};
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let config = Config::new();
+        for (amount, currency, tiers) in [
+            (1_250, "USD", vec![(10, 1_100), (100, 950)]),
+            (123_456_789_012, "ICP", vec![(50, 100_000_001)]),
+        ] {
+            let listing = Listing {
+                id: 7,
+                title: "Olive oil".to_string(),
+                date: 1,
+                description: "Cold pressed, 5l cans".to_string(),
+                category: "Food".to_string(),
+                price: Price { amount, currency: currency.to_string() },
+                min_order_quantity: 5,
+                price_tiers: tiers.iter().map(|(min_quantity, unit_amount)| PriceTier { min_quantity: *min_quantity, unit_amount: *unit_amount }).collect(),
+                amount: 500,
+                owner_id: "seller".to_string(),
+                images_id: vec![3, 4],
+                categories_path: "Food/Oils".to_string(),
+                attributes: vec![ListingAttribute { key: "origin".to_string(), value: AttributeValue::Text("Crete".to_string()) }],
+                reviews: None,
+                hidden: false,
+                favorites_count: 0,
+                status: ListingStatus::Active,
+                expires_at: 2,
+                deleted_at: None,
+            };
+
+            let uploaded = vec!["data:image/png;base64,iVBORw0KGgo=".to_string(), "data:image/jpeg;base64,/9j/4AAQ".to_string()];
+            let stored: Vec<String> = uploaded.iter().map(|image| base64::engine::general_purpose::STANDARD.encode(image)).collect();
+
+            let export = ListingExport::new(&listing, &config, &stored).listing;
+            let price = config.parse_price(export.price, export.currency.clone()).unwrap();
+            assert_eq!(price, listing.price);
+
+            let pricing = export.wholesale_pricing.unwrap();
+            assert_eq!(pricing.min_order_quantity, listing.min_order_quantity);
+            assert_eq!(config.parse_price_tiers(pricing.price_tiers, currency).unwrap(), listing.price_tiers);
+            assert_eq!(export.attributes, listing.attributes);
+            assert_eq!(export.images_strings, uploaded);
+            assert!(!export.draft);
+        }
+    }
+}
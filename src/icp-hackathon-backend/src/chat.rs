@@ -1,17 +1,51 @@
 use candid::{CandidType, Deserialize};
-use ic_cdk::api::time;
+use crate::clock::time;
 use std::collections::HashMap;
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
 use serde::Serialize;
+use crate::attachment::{self, Attachment, AttachmentInput, AttachmentKind};
+use crate::notification::{self, preview, NotificationKind};
+use crate::moderation::ensure_not_banned;
+use crate::handlers;
+
+static AMOUNT_OF_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static AMOUNT_OF_CONVERSATIONS: AtomicU64 = AtomicU64::new(0);
+
+pub const MAX_MESSAGE_LEN: usize = 2000;
+pub const MAX_CLIENT_ID_LEN: usize = 64;
+pub const MAX_HISTORY_PAGE_SIZE: u64 = 100;
+pub const MAX_SYNC_MESSAGES: usize = 100; // per conversation
+pub const MESSAGE_EDIT_WINDOW_NS: u64 = 15 * 60 * 1_000_000_000;
+pub const MAX_REACTION_LEN: usize = 32; // bytes, enough for emoji with modifiers
+pub const MAX_REACTIONS_PER_USER: usize = 10; // per message
+// Clients refresh their typing status while typing, stale entries are ignored after this long
+// and clients should stop showing a typing indicator that was not refreshed for as long
+pub const TYPING_TIMEOUT_NS: u64 = 10 * 1_000_000_000;
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct Message {
     pub id: String,
+    pub seq: u64, // per-conversation counter starting at 1, increases by one for every message
     pub sender_id: String,
     pub content: String,
-    pub message_type: String, // "text", "image", etc.
+    pub message_type: String, // "text", "image", "file", "listing" or "offer"
+    pub attachments: Vec<Attachment>,
+    pub listing_id: Option<u64>, // listing shared in the message
+    pub offer_id: Option<u64>,
     pub timestamp: u64,
-    pub read: bool,
+    pub hidden: bool,
+    pub client_id: Option<String>, // set by the sending client, used to deduplicate retries
+    pub reactions: Vec<Reaction>,
+    pub edited_at: Option<u64>,
+    pub deleted_at: Option<u64>, // tombstone, content and attachments are removed
+    pub updated_at: Option<u64>, // last edit, deletion or reaction, used by sync
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct Reaction {
+    pub user_id: String,
+    pub emoji: String,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
@@ -21,46 +55,140 @@ pub struct Conversation {
     pub listing_id: u64,
     pub listing_title: String,
     pub messages: Vec<Message>,
+    pub last_seq: u64, // seq of the newest message, 0 when empty
     pub created_at: u64,
     pub last_message_time: Option<u64>,
-    pub unread_counts: HashMap<String, u32>, // user_id -> unread_count
+    pub read_receipts: HashMap<String, ReadReceipt>, // user_id -> newest message they have read
     pub typing_users: HashMap<String, bool>, // user_id -> is_typing
+    pub typing_expires_at: HashMap<String, u64>, // user_id -> time the typing status expires
 }
 
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct ReadReceipt {
+    pub last_read_seq: u64,
+    pub read_at: u64,
+}
+
+/// Message as sent by clients, `content` may be empty when it has attachments or shares a listing.
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct WSMessage {
-    pub message_type: String,
-    pub conversation_id: String,
-    pub data: String,
+pub struct MessageInput {
+    pub content: String,
+    pub attachments: Vec<AttachmentInput>,
+    pub listing_id: Option<u64>,
 }
 
+impl MessageInput {
+    fn message_type(&self) -> &'static str {
+        if self.listing_id.is_some() {
+            "listing"
+        } else if self.attachments.iter().any(|a| a.kind == AttachmentKind::File) {
+            "file"
+        } else if !self.attachments.is_empty() {
+            "image"
+        } else {
+            "text"
+        }
+    }
+}
+
+/// Conversation without its messages, for conversation lists.
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct TypingStatus {
-    pub conversation_id: String,
-    pub user_id: String,
-    pub is_typing: bool,
+pub struct ConversationSummary {
+    pub id: String,
+    pub participants: Vec<String>,
+    pub listing_id: u64,
+    pub listing_title: String,
+    pub created_at: u64,
+    pub last_message_time: Option<u64>,
+    pub last_seq: u64,
+    pub last_message_sender: Option<String>,
+    pub last_message_preview: Option<String>,
+    pub unread_count: u32,
+    pub typing_users: Vec<String>,
+}
+
+/// Messages in ascending `seq` order, `has_more` tells whether older (or newer) messages remain.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    pub has_more: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ConversationUpdate {
+    pub summary: ConversationSummary,
+    pub messages: Vec<Message>, // at most MAX_SYNC_MESSAGES, the oldest ones first
+    pub has_more: bool,
+    pub changed_messages: Vec<Message>, // older messages edited, deleted or reacted to since the last sync
+    pub hidden_message_ids: Vec<String>, // older messages hidden by a moderator since the last sync
+    pub read_receipts: HashMap<String, ReadReceipt>, // receipts that moved since the last sync
+}
+
+/// Everything that changed since the client's last sync, `server_time` is the next `since` value.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ChatSync {
+    pub server_time: u64,
+    pub updates: Vec<ConversationUpdate>,
 }
 
 impl Message {
     pub fn new(sender_id: String, content: String, message_type: String) -> Self {
         let timestamp = time();
-        let id = format!("{}-{}", sender_id, timestamp);
+        let id = format!("msg-{}", AMOUNT_OF_MESSAGES.fetch_add(1, Ordering::SeqCst));
         
         Self {
             id,
+            seq: 0, // assigned when the message is added to a conversation
             sender_id,
             content,
             message_type,
+            attachments: Vec::new(),
+            listing_id: None,
+            offer_id: None,
             timestamp,
-            read: false,
+            hidden: false,
+            client_id: None,
+            reactions: Vec::new(),
+            edited_at: None,
+            deleted_at: None,
+            updated_at: None,
+        }
+    }
+
+    /// Short text for notifications and conversation lists.
+    pub fn preview(&self) -> String {
+        if self.hidden {
+            return "[message hidden]".to_string();
         }
+        if self.deleted_at.is_some() {
+            return "[message deleted]".to_string();
+        }
+        if !self.content.trim().is_empty() {
+            return preview(&self.content);
+        }
+        match self.message_type.as_str() {
+            "image" => "[image]".to_string(),
+            "file" => "[file]".to_string(),
+            "listing" => "[listing]".to_string(),
+            "offer" => "[offer]".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Clears the content but keeps the entry, so `seq` stays contiguous. Returns the attachments to release.
+    fn tombstone(&mut self, now: u64) -> Vec<Attachment> {
+        self.content.clear();
+        self.listing_id = None;
+        self.reactions.clear();
+        self.deleted_at = Some(now);
+        std::mem::take(&mut self.attachments)
     }
 }
 
 impl Conversation {
     pub fn new(listing_id: u64, listing_title: String, participants: Vec<String>) -> Self {
         let timestamp = time();
-        let id = format!("conv-{}-{}", listing_id, timestamp);
+        let id = format!("conv-{}", AMOUNT_OF_CONVERSATIONS.fetch_add(1, Ordering::SeqCst));
         
         Self {
             id,
@@ -68,43 +196,128 @@ impl Conversation {
             listing_id,
             listing_title,
             messages: Vec::new(),
+            last_seq: 0,
             created_at: timestamp,
             last_message_time: None,
-            unread_counts: participants.iter().map(|p| (p.clone(), 0)).collect(),
+            read_receipts: HashMap::new(),
             typing_users: HashMap::new(),
+            typing_expires_at: HashMap::new(),
         }
     }
 
-    pub fn add_message(&mut self, message: Message) {
-        // Update unread counts for all participants except sender
-        for participant in &self.participants {
-            if participant != &message.sender_id {
-                *self.unread_counts.entry(participant.clone()).or_insert(0) += 1;
-            }
-        }
-        
+    /// Stores the message with the next sequence number and returns the stored copy.
+    pub fn add_message(&mut self, mut message: Message) -> Message {
+        self.last_seq += 1;
+        message.seq = self.last_seq;
         self.last_message_time = Some(message.timestamp);
-        self.messages.push(message);
+        self.messages.push(message.clone());
+
+        // Senders have read everything up to their own message
+        self.read_receipts.insert(message.sender_id.clone(), ReadReceipt {
+            last_read_seq: message.seq,
+            read_at: message.timestamp,
+        });
+        message
     }
 
-    pub fn mark_as_read(&mut self, user_id: &str) {
-        self.unread_counts.insert(user_id.to_string(), 0);
-        
-        // Mark messages as read for this user
-        for message in &mut self.messages {
-            if message.sender_id != user_id {
-                message.read = true;
-            }
+    pub fn last_read_seq(&self, user_id: &str) -> u64 {
+        self.read_receipts.get(user_id).map_or(0, |receipt| receipt.last_read_seq)
+    }
+
+    /// Visible messages from other participants newer than the user's read receipt.
+    pub fn unread_count(&self, user_id: &str) -> u32 {
+        let last_read_seq = self.last_read_seq(user_id);
+        let start = self.messages.partition_point(|m| m.seq <= last_read_seq);
+
+        self.messages[start..]
+            .iter()
+            .filter(|m| !m.hidden && m.deleted_at.is_none() && m.sender_id != user_id)
+            .count() as u32
+    }
+
+    pub fn summary(&self, user_id: &str, now: u64) -> ConversationSummary {
+        let last_message = self.messages.iter().rev().find(|m| !m.hidden);
+
+        ConversationSummary {
+            id: self.id.clone(),
+            participants: self.participants.clone(),
+            listing_id: self.listing_id,
+            listing_title: self.listing_title.clone(),
+            created_at: self.created_at,
+            last_message_time: self.last_message_time,
+            last_seq: self.last_seq,
+            last_message_sender: last_message.map(|m| m.sender_id.clone()),
+            last_message_preview: last_message.map(|m| m.preview()),
+            unread_count: self.unread_count(user_id),
+            typing_users: self
+                .typing_users
+                .keys()
+                .filter(|u| *u != user_id && self.typing_expires_at.get(*u).is_some_and(|expires_at| *expires_at > now))
+                .cloned()
+                .collect(),
         }
     }
 
-    pub fn set_typing_status(&mut self, user_id: &str, is_typing: bool) {
+    /// Page of visible messages before `before_seq` (the newest ones if None), or after `after_seq`.
+    pub fn history(&self, before_seq: Option<u64>, after_seq: Option<u64>, limit: u64) -> MessagePage {
+        let limit = limit.min(MAX_HISTORY_PAGE_SIZE) as usize;
+        let visible = |m: &&Message| !m.hidden;
+
+        if let Some(after_seq) = after_seq {
+            // Messages are stored in seq order
+            let start = self.messages.partition_point(|m| m.seq <= after_seq);
+            let mut newer = self.messages[start..].iter().filter(visible);
+            let messages: Vec<Message> = newer.by_ref().take(limit).cloned().collect();
+            return MessagePage { messages, has_more: newer.next().is_some() };
+        }
+
+        let end = before_seq.map_or(self.messages.len(), |before_seq| self.messages.partition_point(|m| m.seq < before_seq));
+        let mut older = self.messages[..end].iter().rev().filter(visible);
+        let mut messages: Vec<Message> = older.by_ref().take(limit).cloned().collect();
+        messages.reverse();
+        MessagePage { messages, has_more: older.next().is_some() }
+    }
+
+    /// Moves the user's read receipt to `up_to_seq` (the newest message if None), it never moves backwards.
+    /// Returns the new receipt, or None if it did not change.
+    pub fn mark_as_read(&mut self, user_id: &str, up_to_seq: Option<u64>, now: u64) -> Option<ReadReceipt> {
+        let seq = up_to_seq.unwrap_or(self.last_seq).min(self.last_seq);
+        if seq <= self.last_read_seq(user_id) {
+            return None;
+        }
+
+        let receipt = ReadReceipt {
+            last_read_seq: seq,
+            read_at: now,
+        };
+        self.read_receipts.insert(user_id.to_string(), receipt.clone());
+        Some(receipt)
+    }
+
+    pub fn set_typing_status(&mut self, user_id: &str, is_typing: bool, now: u64) {
         if is_typing {
             self.typing_users.insert(user_id.to_string(), true);
+            self.typing_expires_at.insert(user_id.to_string(), now + TYPING_TIMEOUT_NS);
         } else {
             self.typing_users.remove(user_id);
+            self.typing_expires_at.remove(user_id);
         }
     }
+
+    /// Clears typing statuses that were not refreshed in time, returns the affected users.
+    pub fn expire_typing_statuses(&mut self, now: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .typing_users
+            .keys()
+            .filter(|user_id| self.typing_expires_at.get(*user_id).is_none_or(|expires_at| *expires_at <= now))
+            .cloned()
+            .collect();
+
+        for user_id in &expired {
+            self.set_typing_status(user_id, false, now);
+        }
+        expired
+    }
 }
 
 // Thread-local storage for chat data
@@ -114,6 +327,16 @@ thread_local! {
     pub static CONVERSATION_KEYS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new()); // key -> conversation_id mapping
 }
 
+/// Id counters, saved across upgrades with the conversations.
+pub fn counters() -> (u64, u64) {
+    (AMOUNT_OF_MESSAGES.load(Ordering::SeqCst), AMOUNT_OF_CONVERSATIONS.load(Ordering::SeqCst))
+}
+
+pub fn restore_counters(messages: u64, conversations: u64) {
+    AMOUNT_OF_MESSAGES.store(messages, Ordering::SeqCst);
+    AMOUNT_OF_CONVERSATIONS.store(conversations, Ordering::SeqCst);
+}
+
 pub fn get_conversation_key(listing_id: u64, user1: &str, user2: &str) -> String {
     let (a, b) = if user1 < user2 { (user1, user2) } else { (user2, user1) };
     format!("{}:{}:{}", listing_id, a, b)
@@ -132,10 +355,12 @@ pub fn get_or_create_conversation(listing_id: u64, listing_title: String, user1:
     
     if let Some(conv_id) = existing_conv_id {
         ic_cdk::print(&format!("Found existing conversation with ID: {}", conv_id));
-        // Return existing conversation
-        CONVERSATIONS.with(|convs| {
+        // Return existing conversation, without the messages moderators hid
+        let mut conversation = CONVERSATIONS.with(|convs| {
             convs.borrow().get(&conv_id).cloned().unwrap()
-        })
+        });
+        conversation.messages.retain(|message| !message.hidden);
+        conversation
     } else {
         // Create new conversation
         let participants = vec![user1.clone(), user2.clone()];
@@ -165,20 +390,96 @@ pub fn get_or_create_conversation(listing_id: u64, listing_title: String, user1:
     }
 }
 
-pub fn add_message_to_conversation(conversation_id: &str, message: Message) -> Result<(), String> {
-    CONVERSATIONS.with(|convs| {
+/// Single entry point for new chat messages, used by both the candid API and the WebSocket handlers.
+/// A retry with an already used `client_id` returns the stored message instead of adding it again.
+pub fn send_message(conversation_id: &str, sender_id: &str, input: MessageInput, client_id: Option<String>) -> Result<Message, String> {
+    ensure_not_banned(sender_id)?;
+
+    if let Some(client_id) = &client_id {
+        if client_id.is_empty() || client_id.len() > MAX_CLIENT_ID_LEN {
+            return Err(format!("Client message id must have between 1 and {} characters", MAX_CLIENT_ID_LEN));
+        }
+        if let Some(existing) = find_message_by_client_id(conversation_id, sender_id, client_id) {
+            return Ok(existing);
+        }
+    }
+
+    if input.content.trim().is_empty() && input.attachments.is_empty() && input.listing_id.is_none() {
+        return Err("Message cannot be empty".to_string());
+    }
+    if input.content.len() > MAX_MESSAGE_LEN {
+        return Err(format!("Message cannot be longer than {} characters", MAX_MESSAGE_LEN));
+    }
+    attachment::validate_attachments(&input.attachments)?;
+    if let Some(listing_id) = input.listing_id {
+        let shared = crate::LISTINGS.with(|listings| listings.borrow().iter().any(|l| l.id == listing_id && l.is_public()));
+        if !shared {
+            return Err("Listing not found".to_string());
+        }
+    }
+
+    // Checked before storing attachments so a rejected message leaves nothing behind
+    if !CONVERSATIONS.with(|convs| convs.borrow().contains_key(conversation_id)) {
+        return Err("Conversation not found".to_string());
+    }
+    if !is_participant(conversation_id, sender_id) {
+        return Err("Not a participant in this conversation".to_string());
+    }
+
+    let message_type = input.message_type().to_string();
+    let mut message = Message::new(sender_id.to_string(), input.content, message_type);
+    message.attachments = attachment::store_attachments(conversation_id, input.attachments);
+    message.listing_id = input.listing_id;
+    message.client_id = client_id;
+    let message = add_message_to_conversation(conversation_id, message)?;
+
+    handlers::broadcast_message_to_conversation(conversation_id, &message);
+
+    Ok(message)
+}
+
+/// Posts an offer to the conversation, `content` is its human readable summary.
+pub fn send_offer_message(conversation_id: &str, sender_id: &str, offer_id: u64, content: String) -> Result<Message, String> {
+    let mut message = Message::new(sender_id.to_string(), content, "offer".to_string());
+    message.offer_id = Some(offer_id);
+    let message = add_message_to_conversation(conversation_id, message)?;
+
+    handlers::broadcast_message_to_conversation(conversation_id, &message);
+
+    Ok(message)
+}
+
+pub fn add_message_to_conversation(conversation_id: &str, message: Message) -> Result<Message, String> {
+    let (message, recipients) = CONVERSATIONS.with(|convs| {
         let mut convs = convs.borrow_mut();
         
         if let Some(conversation) = convs.get_mut(conversation_id) {
-            conversation.add_message(message);
-            Ok(())
+            if !conversation.participants.contains(&message.sender_id) {
+                return Err("Not a participant in this conversation".to_string());
+            }
+
+            let message = conversation.add_message(message);
+            let recipients = conversation.participants.iter().filter(|p| **p != message.sender_id).cloned().collect::<Vec<String>>();
+            Ok((message, recipients))
         } else {
             Err("Conversation not found".to_string())
         }
-    })
+    })?;
+
+    // Connected recipients already get the message itself, so the notification is only kept in the inbox
+    for recipient in recipients {
+        notification::store(&recipient, NotificationKind::NewMessage {
+            conversation_id: conversation_id.to_string(),
+            message_id: message.id.clone(),
+            sender_id: message.sender_id.clone(),
+            preview: message.preview(),
+        });
+    }
+
+    Ok(message)
 }
 
-pub fn get_user_conversations(user_id: &str) -> Vec<Conversation> {
+fn get_user_conversations(user_id: &str) -> Vec<Conversation> {
     USER_CONVERSATIONS.with(|user_convs| {
         let user_convs = user_convs.borrow();
         
@@ -187,7 +488,11 @@ pub fn get_user_conversations(user_id: &str) -> Vec<Conversation> {
                 let convs = convs.borrow();
                 conv_ids.iter()
                     .filter_map(|id| convs.get(id))
-                    .cloned()
+                    .map(|conversation| {
+                        let mut conversation = conversation.clone();
+                        conversation.messages.retain(|message| !message.hidden);
+                        conversation
+                    })
                     .collect()
             })
         } else {
@@ -196,28 +501,619 @@ pub fn get_user_conversations(user_id: &str) -> Vec<Conversation> {
     })
 }
 
-pub fn mark_conversation_as_read(conversation_id: &str, user_id: &str) -> Result<(), String> {
+pub fn get_conversation_summaries(user_id: &str) -> Vec<ConversationSummary> {
+    let now = time();
+    let mut summaries: Vec<ConversationSummary> = get_user_conversations(user_id)
+        .iter()
+        .map(|conversation| conversation.summary(user_id, now))
+        .collect();
+
+    // Most recently active first
+    summaries.sort_by_key(|summary| std::cmp::Reverse(summary.last_message_time.unwrap_or(summary.created_at)));
+    summaries
+}
+
+pub fn get_history(conversation_id: &str, user_id: &str, before_seq: Option<u64>, after_seq: Option<u64>, limit: u64) -> Result<MessagePage, String> {
+    if before_seq.is_some() && after_seq.is_some() {
+        return Err("Use either before_seq or after_seq, not both".to_string());
+    }
+
+    CONVERSATIONS.with(|convs| {
+        match convs.borrow().get(conversation_id) {
+            Some(conversation) if conversation.participants.iter().any(|p| p == user_id) => {
+                Ok(conversation.history(before_seq, after_seq, limit))
+            }
+            Some(_) => Err("Not a participant in this conversation".to_string()),
+            None => Err("Conversation not found".to_string()),
+        }
+    })
+}
+
+/// Conversations with activity after `since`, with the messages the client missed.
+pub fn sync(user_id: &str, since: u64) -> ChatSync {
+    let now = time();
+    let updates = CONVERSATIONS.with(|convs| {
+        let convs = convs.borrow();
+        let conv_ids = USER_CONVERSATIONS.with(|user_convs| user_convs.borrow().get(user_id).cloned().unwrap_or_default());
+
+        conv_ids
+            .iter()
+            .filter_map(|id| convs.get(id))
+            .filter_map(|conversation| {
+                let start = conversation.messages.partition_point(|m| m.timestamp <= since);
+                let (hidden, changed_messages): (Vec<&Message>, Vec<&Message>) = conversation.messages[..start]
+                    .iter()
+                    .filter(|m| m.updated_at.is_some_and(|t| t > since))
+                    .partition(|m| m.hidden);
+                let changed_messages: Vec<Message> = changed_messages.into_iter().cloned().collect();
+                let hidden_message_ids: Vec<String> = hidden.into_iter().map(|m| m.id.clone()).collect();
+                let read_receipts: HashMap<String, ReadReceipt> = conversation
+                    .read_receipts
+                    .iter()
+                    .filter(|(_, receipt)| receipt.read_at > since)
+                    .map(|(user_id, receipt)| (user_id.clone(), receipt.clone()))
+                    .collect();
+
+                let active = conversation.created_at > since || conversation.last_message_time.is_some_and(|t| t > since);
+                if !active && changed_messages.is_empty() && hidden_message_ids.is_empty() && read_receipts.is_empty() {
+                    return None;
+                }
+
+                let mut missed = conversation.messages[start..].iter().filter(|m| !m.hidden);
+                let messages: Vec<Message> = missed.by_ref().take(MAX_SYNC_MESSAGES).cloned().collect();
+
+                Some(ConversationUpdate {
+                    summary: conversation.summary(user_id, now),
+                    messages,
+                    has_more: missed.next().is_some(),
+                    changed_messages,
+                    hidden_message_ids,
+                    read_receipts,
+                })
+            })
+            .collect()
+    });
+
+    ChatSync {
+        server_time: now,
+        updates,
+    }
+}
+
+/// Lets the sender change the text of their message within the edit window.
+pub fn edit_message(conversation_id: &str, message_id: &str, user_id: &str, content: String) -> Result<Message, String> {
+    ensure_not_banned(user_id)?;
+    if content.len() > MAX_MESSAGE_LEN {
+        return Err(format!("Message cannot be longer than {} characters", MAX_MESSAGE_LEN));
+    }
+
+    let message = update_message(conversation_id, message_id, user_id, |message, now| {
+        ensure_editable(message, user_id, now)?;
+        if content.trim().is_empty() && message.attachments.is_empty() && message.listing_id.is_none() {
+            return Err("Message cannot be empty".to_string());
+        }
+
+        message.content = content;
+        message.edited_at = Some(now);
+        Ok(())
+    })?;
+
+    notification::update_message_preview(&participants_of(conversation_id), conversation_id, message_id, message.preview());
+    handlers::broadcast_message_edited(conversation_id, &message, user_id);
+    Ok(message)
+}
+
+/// Replaces the message with a tombstone, so the sequence numbers stay without gaps.
+pub fn delete_message(conversation_id: &str, message_id: &str, user_id: &str) -> Result<Message, String> {
+    let mut removed_attachments = Vec::new();
+
+    let message = update_message(conversation_id, message_id, user_id, |message, now| {
+        ensure_editable(message, user_id, now)?;
+
+        removed_attachments = message.tombstone(now);
+        Ok(())
+    })?;
+
+    attachment::release_attachments(&removed_attachments);
+    notification::update_message_preview(&participants_of(conversation_id), conversation_id, message_id, message.preview());
+    handlers::broadcast_message_deleted(conversation_id, &message, user_id);
+    Ok(message)
+}
+
+pub fn add_reaction(conversation_id: &str, message_id: &str, user_id: &str, emoji: String) -> Result<Message, String> {
+    let emoji = emoji.trim().to_string();
+    if emoji.len() > MAX_REACTION_LEN || !is_emoji(&emoji) {
+        return Err("Reaction must be an emoji".to_string());
+    }
+
+    let message = update_message(conversation_id, message_id, user_id, |message, _| {
+        if message.deleted_at.is_some() {
+            return Err("Message was deleted".to_string());
+        }
+        if message.reactions.iter().any(|r| r.user_id == user_id && r.emoji == emoji) {
+            return Ok(());
+        }
+        if message.reactions.iter().filter(|r| r.user_id == user_id).count() >= MAX_REACTIONS_PER_USER {
+            return Err(format!("You can add at most {} reactions to a message", MAX_REACTIONS_PER_USER));
+        }
+
+        message.reactions.push(Reaction {
+            user_id: user_id.to_string(),
+            emoji,
+        });
+        Ok(())
+    })?;
+
+    handlers::broadcast_reactions_updated(conversation_id, &message, user_id);
+    Ok(message)
+}
+
+pub fn remove_reaction(conversation_id: &str, message_id: &str, user_id: &str, emoji: String) -> Result<Message, String> {
+    let message = update_message(conversation_id, message_id, user_id, |message, _| {
+        message.reactions.retain(|r| !(r.user_id == user_id && r.emoji == emoji.trim()));
+        Ok(())
+    })?;
+
+    handlers::broadcast_reactions_updated(conversation_id, &message, user_id);
+    Ok(message)
+}
+
+fn ensure_editable(message: &Message, user_id: &str, now: u64) -> Result<(), String> {
+    if message.sender_id != user_id {
+        return Err("Only the sender can change this message".to_string());
+    }
+    if message.deleted_at.is_some() {
+        return Err("Message was deleted".to_string());
+    }
+    if message.offer_id.is_some() {
+        return Err("Offer messages cannot be changed, respond to the offer instead".to_string());
+    }
+    if message.timestamp + MESSAGE_EDIT_WINDOW_NS < now {
+        return Err("Messages can only be changed shortly after they are sent".to_string());
+    }
+    Ok(())
+}
+
+/// True if `text` is made of emoji only, including keycaps, flags, skin tones and ZWJ sequences.
+fn is_emoji(text: &str) -> bool {
+    let is_pictograph = |c: char| {
+        matches!(c as u32,
+            0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139 | 0x24C2 | 0x2934 | 0x2935
+            | 0x3030 | 0x303D | 0x3297 | 0x3299
+            | 0x2190..=0x21FF   // arrows
+            | 0x2300..=0x23FF   // technical, e.g. watch and hourglass
+            | 0x25A0..=0x25FF   // geometric shapes
+            | 0x2600..=0x27BF   // symbols and dingbats
+            | 0x2B00..=0x2BFF   // stars and arrows
+            | 0x1F000..=0x1FAFF // pictographs, flags and skin tones
+        )
+    };
+    let is_modifier = |c: char| {
+        matches!(c as u32,
+            0x200D            // zero width joiner
+            | 0xFE0E | 0xFE0F // variation selectors
+            | 0x20E3          // combining keycap
+            | 0xE0020..=0xE007F // tags of subdivision flags
+        )
+    };
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut has_emoji = false;
+    for (index, c) in chars.iter().enumerate() {
+        if is_pictograph(*c) {
+            has_emoji = true;
+        } else if matches!(c, '0'..='9' | '#' | '*') {
+            // Keycap bases only count when followed by the keycap mark, e.g. "1️⃣"
+            let rest = &chars[index + 1..];
+            if !(rest.first() == Some(&'\u{20E3}') || rest.starts_with(&['\u{FE0F}', '\u{20E3}'])) {
+                return false;
+            }
+            has_emoji = true;
+        } else if !is_modifier(*c) {
+            return false;
+        }
+    }
+    has_emoji
+}
+
+fn participants_of(conversation_id: &str) -> Vec<String> {
+    CONVERSATIONS.with(|convs| {
+        convs.borrow().get(conversation_id).map(|conversation| conversation.participants.clone()).unwrap_or_default()
+    })
+}
+
+// Applies `change` to a visible message of a conversation the user takes part in
+fn update_message<F>(conversation_id: &str, message_id: &str, user_id: &str, change: F) -> Result<Message, String>
+where
+    F: FnOnce(&mut Message, u64) -> Result<(), String>,
+{
     CONVERSATIONS.with(|convs| {
         let mut convs = convs.borrow_mut();
+
+        let conversation = match convs.get_mut(conversation_id) {
+            Some(conversation) => conversation,
+            None => return Err("Conversation not found".to_string()),
+        };
+        if !conversation.participants.iter().any(|p| p == user_id) {
+            return Err("Not a participant in this conversation".to_string());
+        }
+
+        match conversation.messages.iter_mut().find(|m| m.id == message_id && !m.hidden) {
+            Some(message) => {
+                let now = time();
+                change(message, now)?;
+                message.updated_at = Some(now);
+                Ok(message.clone())
+            }
+            None => Err("Message not found".to_string()),
+        }
+    })
+}
+
+/// Shared by the candid API and the WebSocket handlers, other participants are told about the new receipt.
+pub fn mark_conversation_as_read(conversation_id: &str, user_id: &str, up_to_seq: Option<u64>) -> Result<ReadReceipt, String> {
+    let (receipt, changed) = CONVERSATIONS.with(|convs| {
+        let mut convs = convs.borrow_mut();
         
         if let Some(conversation) = convs.get_mut(conversation_id) {
-            conversation.mark_as_read(user_id);
-            Ok(())
+            if !conversation.participants.iter().any(|p| p == user_id) {
+                return Err("Not a participant in this conversation".to_string());
+            }
+
+            match conversation.mark_as_read(user_id, up_to_seq, time()) {
+                Some(receipt) => Ok((receipt, true)),
+                None => Ok((conversation.read_receipts.get(user_id).cloned().unwrap_or_default(), false)),
+            }
         } else {
             Err("Conversation not found".to_string())
         }
-    })
+    })?;
+
+    if changed {
+        handlers::broadcast_read_receipt_to_conversation(conversation_id, user_id, &receipt);
+    }
+
+    Ok(receipt)
 }
 
+/// Shared by the candid API and the WebSocket handlers, `user_id` must be the authenticated caller.
 pub fn update_typing_status(conversation_id: &str, user_id: &str, is_typing: bool) -> Result<(), String> {
-    CONVERSATIONS.with(|convs| {
+    let expired = CONVERSATIONS.with(|convs| {
         let mut convs = convs.borrow_mut();
         
         if let Some(conversation) = convs.get_mut(conversation_id) {
-            conversation.set_typing_status(user_id, is_typing);
-            Ok(())
+            if !conversation.participants.iter().any(|p| p == user_id) {
+                return Err("Not a participant in this conversation".to_string());
+            }
+
+            // Stale statuses are dropped lazily here instead of on a timer
+            let now = time();
+            let expired = conversation.expire_typing_statuses(now);
+            conversation.set_typing_status(user_id, is_typing, now);
+            Ok(expired)
         } else {
             Err("Conversation not found".to_string())
         }
+    })?;
+
+    for expired_user_id in expired.iter().filter(|expired_user_id| *expired_user_id != user_id) {
+        handlers::broadcast_typing_status_to_conversation(conversation_id, expired_user_id, false);
+    }
+    handlers::broadcast_typing_status_to_conversation(conversation_id, user_id, is_typing);
+
+    Ok(())
+}
+
+/// Clears the typing status of the user in all of their conversations, e.g. when they disconnect.
+pub fn clear_typing_status(user_id: &str) {
+    let conversation_ids: Vec<String> = CONVERSATIONS.with(|convs| {
+        let mut convs = convs.borrow_mut();
+
+        convs
+            .values_mut()
+            .filter(|conversation| conversation.typing_users.contains_key(user_id))
+            .map(|conversation| {
+                conversation.set_typing_status(user_id, false, time());
+                conversation.id.clone()
+            })
+            .collect()
+    });
+
+    for conversation_id in conversation_ids {
+        handlers::broadcast_typing_status_to_conversation(&conversation_id, user_id, false);
+    }
+}
+
+pub fn find_message(conversation_id: &str, message_id: &str) -> Option<Message> {
+    CONVERSATIONS.with(|convs| {
+        convs.borrow().get(conversation_id).and_then(|conversation| {
+            conversation.messages.iter().find(|m| m.id == message_id).cloned()
+        })
     })
 }
+
+fn find_message_by_client_id(conversation_id: &str, sender_id: &str, client_id: &str) -> Option<Message> {
+    CONVERSATIONS.with(|convs| {
+        convs.borrow().get(conversation_id).and_then(|conversation| {
+            conversation
+                .messages
+                .iter()
+                .rev()
+                .find(|m| m.sender_id == sender_id && m.client_id.as_deref() == Some(client_id))
+                .cloned()
+        })
+    })
+}
+
+/// Attachment data, only readable by participants of the conversation it was sent in.
+pub fn get_attachment(conversation_id: &str, attachment_id: u64, user_id: &str) -> Result<String, String> {
+    if !is_participant(conversation_id, user_id) {
+        return Err("Not a participant in this conversation".to_string());
+    }
+    if attachment::attachment_conversation(attachment_id).as_deref() != Some(conversation_id) {
+        return Err("Attachment not found".to_string());
+    }
+    // Attachments of moderated messages are withheld together with the message
+    let visible = CONVERSATIONS.with(|convs| {
+        convs.borrow().get(conversation_id).is_some_and(|conversation| {
+            conversation
+                .messages
+                .iter()
+                .any(|m| !m.hidden && m.attachments.iter().any(|a| a.id == attachment_id))
+        })
+    });
+    if !visible {
+        return Err("Attachment not found".to_string());
+    }
+
+    crate::load_image(attachment_id).ok_or("Attachment not found".to_string())
+}
+
+pub fn is_participant(conversation_id: &str, user_id: &str) -> bool {
+    CONVERSATIONS.with(|convs| {
+        convs.borrow()
+            .get(conversation_id)
+            .is_some_and(|conversation| conversation.participants.iter().any(|p| p == user_id))
+    })
+}
+
+/// True if the user takes part in any conversation about the listing.
+pub fn has_conversation_about(listing_id: u64, user_id: &str) -> bool {
+    CONVERSATIONS.with(|convs| {
+        convs.borrow().values().any(|conversation| {
+            conversation.listing_id == listing_id && conversation.participants.iter().any(|p| p == user_id)
+        })
+    })
+}
+
+pub fn hide_message(conversation_id: &str, message_id: &str) -> Result<(), String> {
+    let (message, participants) = CONVERSATIONS.with(|convs| {
+        let mut convs = convs.borrow_mut();
+
+        if let Some(conversation) = convs.get_mut(conversation_id) {
+            if let Some(message) = conversation.messages.iter_mut().find(|m| m.id == message_id) {
+                message.hidden = true;
+                message.updated_at = Some(time()); // reported by `sync`
+                return Ok((message.clone(), conversation.participants.clone()));
+            }
+            Err("Message not found".to_string())
+        } else {
+            Err("Conversation not found".to_string())
+        }
+    })?;
+
+    notification::update_message_preview(&participants, conversation_id, message_id, message.preview());
+    Ok(())
+}
+
+/// Moderator delete, the message is tombstoned like a sender delete.
+pub fn remove_message(conversation_id: &str, message_id: &str, moderator_id: &str) -> Result<Message, String> {
+    let (message, participants) = CONVERSATIONS.with(|convs| {
+        let mut convs = convs.borrow_mut();
+
+        if let Some(conversation) = convs.get_mut(conversation_id) {
+            if let Some(message) = conversation.messages.iter_mut().find(|m| m.id == message_id) {
+                let now = time();
+                let attachments = message.tombstone(now);
+                message.updated_at = Some(now);
+                attachment::release_attachments(&attachments);
+                return Ok((message.clone(), conversation.participants.clone()));
+            }
+            Err("Message not found".to_string())
+        } else {
+            Err("Conversation not found".to_string())
+        }
+    })?;
+
+    notification::update_message_preview(&participants, conversation_id, message_id, message.preview());
+    handlers::broadcast_message_deleted(conversation_id, &message, moderator_id);
+    Ok(message)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clock::set_time;
+
+    fn conversation() -> Conversation {
+        Conversation {
+            id: "conv-1".to_string(),
+            participants: vec!["buyer".to_string(), "seller".to_string()],
+            listing_id: 1,
+            listing_title: "Pallet of bricks".to_string(),
+            messages: Vec::new(),
+            last_seq: 0,
+            created_at: 0,
+            last_message_time: None,
+            read_receipts: HashMap::new(),
+            typing_users: HashMap::new(),
+            typing_expires_at: HashMap::new(),
+        }
+    }
+
+    /// Stores a conversation between "buyer" and "seller" and returns its id.
+    pub(crate) fn insert_conversation() -> String {
+        let conversation = conversation();
+        let conversation_id = conversation.id.clone();
+        USER_CONVERSATIONS.with(|user_convs| {
+            for participant in &conversation.participants {
+                user_convs.borrow_mut().entry(participant.clone()).or_default().push(conversation_id.clone());
+            }
+        });
+        CONVERSATIONS.with(|convs| convs.borrow_mut().insert(conversation_id.clone(), conversation));
+        conversation_id
+    }
+
+    /// Adds `count` messages alternating between the participants, with seqs 1..=count.
+    fn add_messages(conversation: &mut Conversation, count: u64) {
+        for i in 1..=count {
+            let sender_id = if i % 2 == 0 { "seller" } else { "buyer" };
+            conversation.add_message(Message::new(sender_id.to_string(), format!("message {}", i), "text".to_string()));
+        }
+    }
+
+    fn seqs(page: &MessagePage) -> Vec<u64> {
+        page.messages.iter().map(|m| m.seq).collect()
+    }
+
+    #[test]
+    fn history_pages_backwards_from_before_seq() {
+        let mut conversation = conversation();
+        add_messages(&mut conversation, 250);
+        conversation.messages[249].hidden = true;
+
+        let latest = conversation.history(None, None, 3);
+        assert_eq!((seqs(&latest), latest.has_more), (vec![247, 248, 249], true));
+
+        let page = conversation.history(Some(241), None, 10);
+        assert_eq!((seqs(&page), page.has_more), ((231..=240).collect(), true));
+
+        let first = conversation.history(Some(5), None, 10);
+        assert_eq!((seqs(&first), first.has_more), (vec![1, 2, 3, 4], false));
+        assert!(conversation.history(Some(1), None, 10).messages.is_empty());
+
+        let newer = conversation.history(None, Some(245), 10);
+        assert_eq!((seqs(&newer), newer.has_more), (vec![246, 247, 248, 249], false));
+    }
+
+    #[test]
+    fn history_pages_are_capped() {
+        let mut conversation = conversation();
+        add_messages(&mut conversation, 250);
+
+        let page = conversation.history(None, None, 1_000);
+        assert_eq!(page.messages.len() as u64, MAX_HISTORY_PAGE_SIZE);
+        assert_eq!(page.messages[0].seq, 251 - MAX_HISTORY_PAGE_SIZE);
+        assert!(page.has_more);
+
+        let conversation_id = insert_conversation();
+        assert!(get_history(&conversation_id, "buyer", Some(10), Some(1), 10).is_err());
+    }
+
+    fn text(content: &str) -> MessageInput {
+        MessageInput {
+            content: content.to_string(),
+            attachments: Vec::new(),
+            listing_id: None,
+        }
+    }
+
+    #[test]
+    fn sync_returns_what_changed_since_the_last_sync() {
+        let conversation_id = insert_conversation();
+        set_time(100);
+        let first = send_message(&conversation_id, "buyer", text("hello"), None).unwrap();
+        let second = send_message(&conversation_id, "seller", text("hi"), None).unwrap();
+
+        let initial = sync("buyer", 0);
+        assert_eq!(initial.server_time, 100);
+        assert_eq!(initial.updates[0].messages.iter().map(|m| m.seq).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(sync("buyer", initial.server_time).updates.is_empty());
+
+        set_time(200);
+        send_message(&conversation_id, "seller", text("still there?"), None).unwrap();
+        edit_message(&conversation_id, &first.id, "buyer", "hello!".to_string()).unwrap();
+        hide_message(&conversation_id, &second.id).unwrap();
+
+        let update = &sync("buyer", 100).updates[0];
+        assert_eq!(update.messages.iter().map(|m| m.seq).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(update.changed_messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), vec!["hello!"]);
+        assert_eq!(update.hidden_message_ids, vec![second.id.clone()]);
+        assert_eq!(update.read_receipts["seller"].last_read_seq, 3);
+        assert!(!update.read_receipts.contains_key("buyer"));
+
+        // A read receipt alone is reported to the other participant
+        set_time(300);
+        mark_conversation_as_read(&conversation_id, "buyer", None).unwrap();
+        let updates = sync("seller", 200).updates;
+        assert!(updates[0].messages.is_empty());
+        assert_eq!(updates[0].read_receipts["buyer"].last_read_seq, 3);
+        assert_eq!(updates[0].summary.unread_count, 0);
+    }
+
+    #[test]
+    fn resent_client_ids_return_the_original_message() {
+        let conversation_id = insert_conversation();
+        let client_id = Some("temp-1".to_string());
+
+        let original = send_message(&conversation_id, "buyer", text("hello"), client_id.clone()).unwrap();
+        let retried = send_message(&conversation_id, "buyer", text("hello"), client_id.clone()).unwrap();
+        assert_eq!((retried.id, retried.seq), (original.id, original.seq));
+
+        // Client ids are scoped to the sender
+        let other = send_message(&conversation_id, "seller", text("hello"), client_id).unwrap();
+        assert_eq!(other.seq, 2);
+        assert_eq!(CONVERSATIONS.with(|convs| convs.borrow()[&conversation_id].messages.len()), 2);
+
+        assert!(send_message(&conversation_id, "buyer", text("hello"), Some(String::new())).is_err());
+        assert!(send_message(&conversation_id, "buyer", text("hello"), Some("x".repeat(MAX_CLIENT_ID_LEN + 1))).is_err());
+    }
+
+    fn not_participant<T>() -> Result<T, String> {
+        Err("Not a participant in this conversation".to_string())
+    }
+
+    #[test]
+    fn non_participants_are_rejected() {
+        let conversation_id = insert_conversation();
+        let input = MessageInput {
+            content: "hello".to_string(),
+            attachments: Vec::new(),
+            listing_id: None,
+        };
+
+        // The candid endpoints pass the caller straight to these
+        assert_eq!(send_message(&conversation_id, "stranger", input, None).map(|m| m.id), not_participant());
+        assert_eq!(mark_conversation_as_read(&conversation_id, "stranger", None).map(|_| ()), not_participant());
+        assert_eq!(update_typing_status(&conversation_id, "stranger", true), not_participant());
+        assert_eq!(get_history(&conversation_id, "stranger", None, None, 10).map(|_| ()), not_participant());
+        assert_eq!(edit_message(&conversation_id, "msg-1", "stranger", "edited".to_string()).map(|_| ()), not_participant());
+        assert_eq!(delete_message(&conversation_id, "msg-1", "stranger").map(|_| ()), not_participant());
+        assert_eq!(get_attachment(&conversation_id, 0, "stranger"), not_participant());
+
+        assert!(CONVERSATIONS.with(|convs| convs.borrow()[&conversation_id].messages.is_empty()));
+    }
+
+    #[test]
+    fn typing_status_expires_on_read() {
+        let mut conversation = conversation();
+        conversation.set_typing_status("buyer", true, 0);
+
+        assert_eq!(conversation.summary("seller", TYPING_TIMEOUT_NS - 1).typing_users, vec!["buyer".to_string()]);
+        assert!(conversation.summary("buyer", 1).typing_users.is_empty());
+        assert!(conversation.summary("seller", TYPING_TIMEOUT_NS).typing_users.is_empty());
+
+        assert_eq!(conversation.expire_typing_statuses(TYPING_TIMEOUT_NS), vec!["buyer".to_string()]);
+        assert!(conversation.typing_users.is_empty());
+    }
+
+    #[test]
+    fn reactions_must_be_emoji() {
+        for emoji in ["👍", "❤️", "1️⃣", "#⃣", "🇵🇱", "👍🏽", "👨‍👩‍👧", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "⭐", "☕"] {
+            assert!(is_emoji(emoji), "{} should be accepted", emoji);
+        }
+        for text in ["", "a", "1", "<>", ":)", "ok👍", "👍 👍", "\u{200D}", "\u{FE0F}"] {
+            assert!(!is_emoji(text), "{:?} should be rejected", text);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+// ic_cdk::api::time traps outside a canister, unit tests read a clock they can set instead
+#[cfg(not(test))]
+pub use ic_cdk::api::time;
+
+#[cfg(test)]
+pub use mock::{set_time, time};
+
+#[cfg(test)]
+pub mod mock {
+    use std::cell::Cell;
+
+    thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(0) };
+    }
+
+    /// Current time in nanoseconds, 0 until a test sets it.
+    pub fn time() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    pub fn set_time(now: u64) {
+        NOW.with(|cell| cell.set(now));
+    }
+}
@@ -1,5 +1,7 @@
 use candid::{CandidType, Deserialize};
+use crate::attribute::{self, AttributeDefinition, AttributeSchema, AttributeType, ListingAttribute};
 use crate::category::Category;
+use crate::price::{Currency, Price, PriceTier, PriceTierInput};
 
 #[derive(Clone, CandidType, Deserialize)]
 pub struct Config {
@@ -8,10 +10,20 @@ pub struct Config {
     pub min_description_len: u16,
     pub min_title_len: u8,
     pub categories: Vec<Category>,
+    pub attribute_schemas: Vec<AttributeSchema>,
     pub min_user_name_len: u8,
     pub max_user_name_len: u16,
     pub min_company_name_len: u8,
     pub max_company_name_len: u16,
+    pub listing_duration_days: u16,
+    pub listing_restore_days: u16,
+    pub currencies: Vec<Currency>,
+    pub max_price_tiers: u8,
+    pub max_import_batch: u16,
+    pub default_currency: String,
+    pub payment_token: String, // currency code of the ICRC-1 ledger used for escrow
+    pub escrow_timeout_days: u16, // paid orders not shipped in time are cancelled and refunded
+    pub delivery_timeout_days: u16, // shipped orders not confirmed by the buyer in time are completed
 }
 
 impl Config {
@@ -25,6 +37,21 @@ impl Config {
             max_user_name_len: 50,
             min_company_name_len: 5,
             max_company_name_len: 50,
+            listing_duration_days: 30,
+            listing_restore_days: 30,
+            currencies: vec![
+                Currency::new("USD", 2),
+                Currency::new("EUR", 2),
+                Currency::new("PLN", 2),
+                Currency::new("ICP", 8),
+                Currency::new("ckBTC", 8),
+            ],
+            default_currency: "USD".to_string(),
+            max_price_tiers: 10,
+            max_import_batch: 200,
+            payment_token: "ICP".to_string(),
+            escrow_timeout_days: 14,
+            delivery_timeout_days: 30,
             categories: vec![
                 Category::new("Electronics".to_string(), Some(vec![
                     Category::new("Mobile Phones".to_string(), None),
@@ -75,6 +102,101 @@ impl Config {
                 ])),
                 
             ],
+            attribute_schemas: vec![
+                AttributeSchema::new("", vec![
+                    AttributeDefinition::new("brand", "Brand", AttributeType::Text, None),
+                    AttributeDefinition::new("unit", "Sales unit", AttributeType::Choice(vec![
+                        "piece".to_string(),
+                        "pack".to_string(),
+                        "box".to_string(),
+                        "pallet".to_string(),
+                        "kg".to_string(),
+                        "l".to_string(),
+                        "m".to_string(),
+                    ]), None),
+                    AttributeDefinition::new("weight", "Weight", AttributeType::Number, Some("kg")),
+                    AttributeDefinition::new("dimensions", "Dimensions (L x W x H)", AttributeType::Text, Some("cm")),
+                    AttributeDefinition::new("origin", "Country of origin", AttributeType::Text, None),
+                    AttributeDefinition::new("ean", "EAN", AttributeType::Ean, None),
+                ]),
+
+                AttributeSchema::new("Electronics", vec![
+                    AttributeDefinition::new("warranty", "Warranty", AttributeType::Number, Some("months")),
+                ]),
+
+                AttributeSchema::new("Fashion", vec![
+                    AttributeDefinition::new("size", "Size", AttributeType::Text, None),
+                    AttributeDefinition::new("material", "Material", AttributeType::Text, None),
+                ]),
+
+                AttributeSchema::new("Agriculture", vec![
+                    AttributeDefinition::new("organic", "Organic", AttributeType::Boolean, None),
+                ]),
+
+                AttributeSchema::new("Books", vec![
+                    AttributeDefinition::new("isbn", "ISBN", AttributeType::Ean, None),
+                    AttributeDefinition::new("language", "Language", AttributeType::Text, None),
+                ]),
+            ],
         }
     }
-}
\ No newline at end of file
+
+    pub fn listing_duration_ns(&self) -> u64 {
+        days_to_ns(self.listing_duration_days)
+    }
+
+    pub fn listing_restore_window_ns(&self) -> u64 {
+        days_to_ns(self.listing_restore_days)
+    }
+
+    pub fn escrow_timeout_ns(&self) -> u64 {
+        days_to_ns(self.escrow_timeout_days)
+    }
+
+    pub fn delivery_timeout_ns(&self) -> u64 {
+        days_to_ns(self.delivery_timeout_days)
+    }
+
+    pub fn find_currency(&self, code: &str) -> Option<&Currency> {
+        self.currencies.iter().find(|currency| currency.code == code)
+    }
+
+    /// Converts a decimal price sent by clients, falling back to the default currency.
+    pub fn parse_price(&self, value: f64, currency: Option<String>) -> Result<Price, String> {
+        let code = currency.unwrap_or_else(|| self.default_currency.clone());
+
+        match self.find_currency(&code) {
+            Some(currency) => Price::from_f64(value, currency),
+            None => Err(format!("Unsupported currency: {}", code)),
+        }
+    }
+
+    pub fn attribute_definitions(&self, categories_path: &str) -> Vec<AttributeDefinition> {
+        attribute::definitions_for(&self.attribute_schemas, categories_path)
+    }
+
+    pub fn validate_attributes(&self, categories_path: &str, attributes: Vec<ListingAttribute>) -> Result<Vec<ListingAttribute>, String> {
+        attribute::validate_attributes(&self.attribute_definitions(categories_path), attributes)
+    }
+
+    pub fn parse_price_tiers(&self, tiers: Vec<PriceTierInput>, currency: &str) -> Result<Vec<PriceTier>, String> {
+        if tiers.len() > self.max_price_tiers as usize {
+            return Err(format!("A listing can have at most {} price tiers.", self.max_price_tiers));
+        }
+
+        tiers
+            .into_iter()
+            .map(|tier| -> Result<PriceTier, String> {
+                let price = self.parse_price(tier.unit_price, Some(currency.to_string()))?;
+                Ok(PriceTier {
+                    min_quantity: tier.min_quantity,
+                    unit_amount: price.amount,
+                })
+            })
+            .collect()
+    }
+}
+
+fn days_to_ns(days: u16) -> u64 {
+    days as u64 * 24 * 60 * 60 * 1_000_000_000
+}
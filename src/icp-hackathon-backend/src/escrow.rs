@@ -0,0 +1,427 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use crate::ledger::{self, Account, TransferArg};
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum EscrowStatus {
+    Funded,
+    Releasing,
+    Released,
+    Refunding,
+    Refunded,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Escrow {
+    pub order_id: u64,
+    pub amount: u64, // smallest token units
+    pub buyer_id: String,
+    pub seller_id: String,
+    pub status: EscrowStatus,
+    pub funded_at: u64,
+    pub settled_at: Option<u64>,
+    pub ledger_block: Option<Nat>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentInstructions {
+    pub ledger_id: Principal,
+    pub account: Account,
+    pub amount: u64,
+}
+
+thread_local! {
+    // Ledger used for payments, payments are disabled until a controller sets it.
+    // Point it at a local ICRC-1 ledger canister for development.
+    pub static LEDGER_ID: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    pub static ESCROWS: RefCell<HashMap<u64, Escrow>> = RefCell::new(HashMap::new());
+}
+
+const ESCROW_SUBACCOUNT_TAG: u8 = 1;
+
+pub fn ledger_id() -> Result<Principal, String> {
+    LEDGER_ID.with(|id| *id.borrow()).ok_or("Payments are not enabled.".to_string())
+}
+
+/// Each order gets its own subaccount of the canister, tagged so it never equals the default one.
+pub fn escrow_subaccount(order_id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0u8; 32];
+    subaccount[0] = ESCROW_SUBACCOUNT_TAG;
+    subaccount[24..].copy_from_slice(&order_id.to_be_bytes());
+    subaccount
+}
+
+#[cfg(not(test))]
+fn canister_id() -> Principal {
+    ic_cdk::id()
+}
+
+#[cfg(test)]
+fn canister_id() -> Principal {
+    ledger::mock::canister_id()
+}
+
+pub fn escrow_account(order_id: u64) -> Account {
+    Account {
+        owner: canister_id(),
+        subaccount: Some(escrow_subaccount(order_id)),
+    }
+}
+
+pub fn get_escrow(order_id: u64) -> Option<Escrow> {
+    ESCROWS.with(|escrows| escrows.borrow().get(&order_id).cloned())
+}
+
+/// Records the escrow as funded once the order subaccount holds at least `amount`,
+/// anything paid beyond `amount` is sent back to the buyer.
+pub async fn fund(order_id: u64, buyer_id: String, seller_id: String, amount: u64, now: u64) -> Result<Escrow, String> {
+    if let Some(escrow) = get_escrow(order_id) {
+        return Err(format!("Order payment is already {:?}.", escrow.status));
+    }
+
+    let ledger_id = ledger_id()?;
+    let balance = ledger::balance_of(ledger_id, escrow_account(order_id)).await?;
+    if balance < amount {
+        return Err(format!("Payment not received yet: expected {}, found {}.", amount, balance));
+    }
+
+    let escrow = ESCROWS.with(|escrows| {
+        let mut escrows = escrows.borrow_mut();
+
+        // Another call may have confirmed the payment while we were waiting for the ledger
+        if let Some(escrow) = escrows.get(&order_id) {
+            return Err(format!("Order payment is already {:?}.", escrow.status));
+        }
+
+        let escrow = Escrow {
+            order_id,
+            amount,
+            buyer_id,
+            seller_id,
+            status: EscrowStatus::Funded,
+            funded_at: now,
+            settled_at: None,
+            ledger_block: None,
+            last_error: None,
+        };
+        escrows.insert(order_id, escrow.clone());
+        Ok(escrow)
+    })?;
+
+    // A failed overpayment refund does not undo the funding, it can be retried once the order is settled
+    if let Err(e) = refund_excess(order_id).await {
+        ESCROWS.with(|escrows| {
+            if let Some(escrow) = escrows.borrow_mut().get_mut(&order_id) {
+                escrow.last_error = Some(e);
+            }
+        });
+    }
+
+    Ok(get_escrow(order_id).unwrap_or(escrow))
+}
+
+/// Sends the buyer whatever the order account holds beyond the escrowed amount, after a release that
+/// is everything left on it. Returns the ledger block of the refund, or None if there was nothing to send.
+pub async fn refund_excess(order_id: u64) -> Result<Option<Nat>, String> {
+    let ledger_id = ledger_id()?;
+    let fee = ledger::fee(ledger_id).await?;
+    let balance = ledger::balance_of(ledger_id, escrow_account(order_id)).await?;
+
+    // Read after the ledger calls, a payout may have finished in the meantime
+    let (buyer_id, held) = match get_escrow(order_id) {
+        Some(escrow) => match escrow.status {
+            EscrowStatus::Funded | EscrowStatus::Releasing => (escrow.buyer_id, escrow.amount),
+            EscrowStatus::Released => (escrow.buyer_id, 0),
+            // Refunds already return the whole balance
+            EscrowStatus::Refunding | EscrowStatus::Refunded => return Ok(None),
+        },
+        None => return Err("Order has not been paid.".to_string()),
+    };
+
+    let held = Nat::from(held);
+    if balance <= held.clone() + fee.clone() {
+        return Ok(None);
+    }
+
+    let owner = Principal::from_text(&buyer_id).map_err(|_| "Invalid recipient principal.".to_string())?;
+    ledger::transfer(ledger_id, TransferArg {
+        from_subaccount: Some(escrow_subaccount(order_id)),
+        to: Account { owner, subaccount: None },
+        amount: balance - held - fee.clone(),
+        fee: Some(fee),
+        memo: Some(order_id.to_be_bytes().to_vec()),
+        created_at_time: None,
+    })
+    .await
+    .map(Some)
+}
+
+pub async fn release(order_id: u64, now: u64) -> Result<Escrow, String> {
+    payout(order_id, EscrowStatus::Releasing, EscrowStatus::Released, now).await
+}
+
+pub async fn refund(order_id: u64, now: u64) -> Result<Escrow, String> {
+    payout(order_id, EscrowStatus::Refunding, EscrowStatus::Refunded, now).await
+}
+
+/// Refunds whatever is left on the account of an order that can no longer be paid, including
+/// payments the buyer never confirmed and transfers that arrived after an earlier refund.
+pub async fn refund_balance(order_id: u64, buyer_id: String, seller_id: String, now: u64) -> Result<Escrow, String> {
+    let ledger_id = ledger_id()?;
+    let fee = ledger::fee(ledger_id).await?;
+    let balance = ledger::balance_of(ledger_id, escrow_account(order_id)).await?;
+
+    if balance <= fee {
+        return Err("There is no payment to refund.".to_string());
+    }
+
+    ESCROWS.with(|escrows| {
+        let mut escrows = escrows.borrow_mut();
+
+        match escrows.get(&order_id).map(|escrow| escrow.status.clone()) {
+            // Record the unconfirmed payment so it goes through the regular refund
+            None | Some(EscrowStatus::Refunded) => {
+                escrows.insert(order_id, Escrow {
+                    order_id,
+                    amount: u64::try_from(balance.0).unwrap_or(u64::MAX),
+                    buyer_id,
+                    seller_id,
+                    status: EscrowStatus::Funded,
+                    funded_at: now,
+                    settled_at: None,
+                    ledger_block: None,
+                    last_error: None,
+                });
+                Ok(())
+            }
+            Some(EscrowStatus::Funded) => Ok(()),
+            Some(status) => Err(format!("Order payment is already {:?}.", status)),
+        }
+    })?;
+
+    refund(order_id, now).await
+}
+
+// Sends the escrowed amount minus the ledger fee to the seller (release),
+// or the whole subaccount balance minus the ledger fee to the buyer (refund)
+async fn payout(order_id: u64, in_progress: EscrowStatus, done: EscrowStatus, now: u64) -> Result<Escrow, String> {
+    let ledger_id = ledger_id()?;
+
+    // Lock the escrow so a concurrent call cannot pay it out twice
+    let (recipient, limit) = ESCROWS.with(|escrows| {
+        let mut escrows = escrows.borrow_mut();
+
+        match escrows.get_mut(&order_id) {
+            Some(escrow) if escrow.status == EscrowStatus::Funded => {
+                escrow.status = in_progress.clone();
+                Ok(if done == EscrowStatus::Released {
+                    (escrow.seller_id.clone(), Some(escrow.amount))
+                } else {
+                    (escrow.buyer_id.clone(), None)
+                })
+            }
+            Some(escrow) => Err(format!("Order payment is already {:?}.", escrow.status)),
+            None => Err("Order has not been paid.".to_string()),
+        }
+    })?;
+
+    let result = transfer_out(ledger_id, order_id, &recipient, limit).await;
+
+    ESCROWS.with(|escrows| {
+        let mut escrows = escrows.borrow_mut();
+        let escrow = escrows.get_mut(&order_id).ok_or("Order has not been paid.".to_string())?;
+
+        match result {
+            Ok(block) => {
+                escrow.status = done;
+                escrow.settled_at = Some(now);
+                escrow.ledger_block = Some(block);
+                escrow.last_error = None;
+                Ok(escrow.clone())
+            }
+            Err(e) => {
+                escrow.status = EscrowStatus::Funded;
+                escrow.last_error = Some(e.clone());
+                Err(e)
+            }
+        }
+    })
+}
+
+// Transfers at most `limit` from the order account, the fee included
+async fn transfer_out(ledger_id: Principal, order_id: u64, recipient: &str, limit: Option<u64>) -> Result<Nat, String> {
+    let owner = Principal::from_text(recipient).map_err(|_| "Invalid recipient principal.".to_string())?;
+    let fee = ledger::fee(ledger_id).await?;
+    let balance = match (ledger::balance_of(ledger_id, escrow_account(order_id)).await?, limit) {
+        (balance, Some(limit)) if balance > limit => Nat::from(limit),
+        (balance, _) => balance,
+    };
+
+    if balance <= fee {
+        return Err("Escrow balance does not cover the ledger fee.".to_string());
+    }
+
+    ledger::transfer(ledger_id, TransferArg {
+        from_subaccount: Some(escrow_subaccount(order_id)),
+        to: Account { owner, subaccount: None },
+        amount: balance - fee.clone(),
+        fee: Some(fee),
+        memo: Some(order_id.to_be_bytes().to_vec()),
+        created_at_time: None,
+    }).await
+}
+
+/// Escrows still holding the payment, checked periodically for orders that stalled.
+pub fn funded_escrows() -> Vec<Escrow> {
+    ESCROWS.with(|escrows| {
+        escrows
+            .borrow()
+            .values()
+            .filter(|escrow| escrow.status == EscrowStatus::Funded)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::mock::{self, block_on, FEE};
+
+    const PRICE: u64 = 1_000_000;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn wallet(id: u8) -> Account {
+        Account { owner: user(id), subaccount: None }
+    }
+
+    fn setup() {
+        LEDGER_ID.with(|id| *id.borrow_mut() = Some(Principal::management_canister()));
+    }
+
+    fn fund_order(order_id: u64) -> Result<Escrow, String> {
+        block_on(fund(order_id, user(1).to_text(), user(2).to_text(), PRICE, 1))
+    }
+
+    #[test]
+    fn funding_waits_for_the_full_payment() {
+        setup();
+        mock::deposit(escrow_account(7), PRICE - 1);
+        assert!(fund_order(7).is_err());
+        assert!(get_escrow(7).is_none());
+
+        mock::deposit(escrow_account(7), 1);
+        assert_eq!(fund_order(7).unwrap().status, EscrowStatus::Funded);
+        assert!(fund_order(7).is_err());
+    }
+
+    #[test]
+    fn release_pays_the_seller() {
+        setup();
+        mock::deposit(escrow_account(7), PRICE);
+        fund_order(7).unwrap();
+
+        let escrow = block_on(release(7, 2)).unwrap();
+        assert_eq!(escrow.status, EscrowStatus::Released);
+        assert_eq!(escrow.settled_at, Some(2));
+        assert_eq!(mock::balance(&wallet(2)), PRICE - FEE);
+        assert_eq!(mock::balance(&escrow_account(7)), 0);
+
+        // A settled escrow cannot be paid out again
+        assert!(block_on(refund(7, 3)).is_err());
+    }
+
+    #[test]
+    fn refund_pays_the_buyer() {
+        setup();
+        mock::deposit(escrow_account(7), PRICE);
+        fund_order(7).unwrap();
+
+        assert_eq!(block_on(refund(7, 2)).unwrap().status, EscrowStatus::Refunded);
+        assert_eq!(mock::balance(&wallet(1)), PRICE - FEE);
+        assert_eq!(mock::balance(&wallet(2)), 0);
+    }
+
+    #[test]
+    fn failed_payouts_revert_to_funded() {
+        setup();
+        mock::deposit(escrow_account(7), PRICE);
+        fund_order(7).unwrap();
+
+        mock::fail_transfers(Some("Ledger call failed: SysTransient"));
+        assert!(block_on(release(7, 2)).is_err());
+        let escrow = get_escrow(7).unwrap();
+        assert_eq!(escrow.status, EscrowStatus::Funded);
+        assert_eq!(escrow.last_error.as_deref(), Some("Ledger call failed: SysTransient"));
+        assert_eq!(mock::balance(&escrow_account(7)), PRICE);
+
+        mock::fail_transfers(None);
+        let escrow = block_on(release(7, 3)).unwrap();
+        assert_eq!(escrow.status, EscrowStatus::Released);
+        assert_eq!(escrow.last_error, None);
+    }
+
+    #[test]
+    fn overpayments_are_returned_to_the_buyer() {
+        setup();
+        mock::deposit(escrow_account(7), PRICE + 50_000);
+
+        let escrow = fund_order(7).unwrap();
+        assert_eq!(escrow.last_error, None);
+        assert_eq!(mock::balance(&wallet(1)), 50_000 - FEE);
+        assert_eq!(mock::balance(&escrow_account(7)), PRICE);
+
+        // An excess below the ledger fee cannot be sent back and is not paid to the seller either
+        mock::deposit(escrow_account(7), FEE);
+        block_on(release(7, 2)).unwrap();
+        assert_eq!(mock::balance(&wallet(2)), PRICE - FEE);
+        assert_eq!(mock::balance(&escrow_account(7)), FEE);
+
+        // Transfers arriving after the release go back to the buyer
+        mock::deposit(escrow_account(7), 30_000);
+        assert!(block_on(refund_excess(7)).unwrap().is_some());
+        assert_eq!(mock::balance(&wallet(1)), 50_000 - FEE + 30_000);
+        assert_eq!(block_on(refund_excess(7)), Ok(None));
+    }
+
+    #[test]
+    fn failed_overpayment_refunds_keep_the_escrow_funded() {
+        setup();
+        mock::deposit(escrow_account(7), PRICE + 50_000);
+
+        mock::fail_transfers(Some("Ledger call failed: SysTransient"));
+        let escrow = fund_order(7).unwrap();
+        assert_eq!(escrow.status, EscrowStatus::Funded);
+        assert_eq!(escrow.last_error.as_deref(), Some("Ledger call failed: SysTransient"));
+
+        mock::fail_transfers(None);
+        block_on(release(7, 2)).unwrap();
+        assert_eq!(mock::balance(&wallet(2)), PRICE - FEE);
+        block_on(refund_excess(7)).unwrap();
+        assert_eq!(mock::balance(&wallet(1)), 50_000 - FEE);
+        assert_eq!(mock::balance(&escrow_account(7)), 0);
+    }
+
+    #[test]
+    fn unconfirmed_and_late_payments_are_refunded() {
+        setup();
+        assert!(block_on(refund_balance(7, user(1).to_text(), user(2).to_text(), 1)).is_err());
+
+        // The buyer paid but the order was cancelled before they confirmed the payment
+        mock::deposit(escrow_account(7), PRICE);
+        let escrow = block_on(refund_balance(7, user(1).to_text(), user(2).to_text(), 2)).unwrap();
+        assert_eq!(escrow.status, EscrowStatus::Refunded);
+        assert_eq!(mock::balance(&wallet(1)), PRICE - FEE);
+
+        // A second transfer after the refund is returned as well
+        mock::deposit(escrow_account(7), PRICE);
+        block_on(refund_balance(7, user(1).to_text(), user(2).to_text(), 3)).unwrap();
+        assert_eq!(mock::balance(&wallet(1)), 2 * (PRICE - FEE));
+        assert_eq!(mock::balance(&escrow_account(7)), 0);
+    }
+}
@@ -1,174 +1,117 @@
-use candid::{CandidType, decode_one, encode_one};
-use ic_cdk::print;
-use serde::{Deserialize, Serialize};
 use ic_websocket_cdk::{
     ClientPrincipal, OnCloseCallbackArgs, OnMessageCallbackArgs, OnOpenCallbackArgs,
     ws_send,
 };
-use crate::chat::{Message, add_message_to_conversation, CONVERSATIONS};
+use crate::chat::{self, Message, MessageInput, ReadReceipt, CONVERSATIONS};
+use crate::offer::Offer;
+use crate::protocol::{ClientEnvelope, ClientEvent, ServerEnvelope, ServerEvent};
 use std::collections::HashMap;
 use std::cell::RefCell;
 
-thread_local! {
-    static CONNECTED_CLIENTS: RefCell<HashMap<String, ClientPrincipal>> = RefCell::new(HashMap::new());
-}
-
-#[derive(CandidType, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-pub struct ChatMessage {
-    pub message_type: String, // "chat_message", "typing_status", "user_joined", "user_left"
-    pub conversation_id: String,
-    pub sender_id: String,
-    pub content: String,
-    pub timestamp: u64,
-}
-
-#[derive(CandidType, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-pub struct TypingStatusMessage {
-    pub message_type: String, // "typing_status"
-    pub conversation_id: String,
-    pub user_id: String,
-    pub is_typing: bool,
-}
-
-#[derive(CandidType, Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
-pub struct AppMessage {
-    pub message_type: String,
-    pub data: String,
-}
-
-impl AppMessage {
-    pub fn candid_serialize(&self) -> Vec<u8> {
-        encode_one(&self).unwrap()
-    }
+// ic_cdk::print panics outside a canister, so unit tests of the handlers do not log
+fn print<S: AsRef<str>>(message: S) {
+    #[cfg(not(test))]
+    ic_cdk::print(message);
+    #[cfg(test)]
+    let _ = message;
 }
 
-impl ChatMessage {
-    pub fn candid_serialize(&self) -> Vec<u8> {
-        encode_one(&self).unwrap()
-    }
-}
-
-impl TypingStatusMessage {
-    pub fn candid_serialize(&self) -> Vec<u8> {
-        encode_one(&self).unwrap()
-    }
+thread_local! {
+    static CONNECTED_CLIENTS: RefCell<HashMap<String, ClientPrincipal>> = RefCell::new(HashMap::new());
 }
 
 pub fn on_open(args: OnOpenCallbackArgs) {
     let client_principal = args.client_principal;
     let user_id = client_principal.to_string();
-    
+
     print(format!("User {} connected via WebSocket", user_id));
-    
+
     // Store the connected client
     CONNECTED_CLIENTS.with(|clients| {
         clients.borrow_mut().insert(user_id.clone(), client_principal);
     });
 
-    // Send welcome message
-    let welcome_msg = AppMessage {
-        message_type: "connection_established".to_string(),
-        data: "Welcome to chat!".to_string(),
-    };
-    
-    let _ = ws_send(client_principal, welcome_msg.candid_serialize());
+    send_event(client_principal, ServerEvent::Connected { user_id });
 }
 
 pub fn on_message(args: OnMessageCallbackArgs) {
     let client_principal = args.client_principal;
     let user_id = client_principal.to_string();
-    
+
     print(format!("Received message from {}", user_id));
-    
-    // Try to decode as AppMessage first
-    if let Ok(app_msg) = decode_one::<AppMessage>(&args.message) {
-        print(format!("Decoded app message: {:?}", app_msg));
-        
-        match app_msg.message_type.as_str() {
-            "chat_message" => {
-                if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&app_msg.data) {
-                    handle_chat_message(chat_msg, client_principal);
-                } else {
-                    print("Failed to parse chat message data");
-                }
-            },
-            "typing_status" => {
-                if let Ok(typing_msg) = serde_json::from_str::<TypingStatusMessage>(&app_msg.data) {
-                    handle_typing_status(typing_msg, client_principal);
-                } else {
-                    print("Failed to parse typing status data");
-                }
-            },
-            _ => {
-                print(format!("Unknown message type: {}", app_msg.message_type));
-            }
+
+    let envelope = match ClientEnvelope::decode(&args.message) {
+        Ok(envelope) => envelope,
+        Err((code, message)) => {
+            print(format!("Rejected message from {}: {}", user_id, message));
+            send_event(client_principal, ServerEvent::Error { code, message });
+            return;
         }
-    } else {
-        print("Failed to decode message as AppMessage");
+    };
+
+    if let Some(reply) = handle_event(&user_id, envelope.event) {
+        send_event(client_principal, reply);
+    }
+}
+
+// Identity always comes from the authenticated client, never from the payload
+fn handle_event(user_id: &str, event: ClientEvent) -> Option<ServerEvent> {
+    match event {
+        ClientEvent::SendMessage { conversation_id, content, client_id, attachments, listing_id } => {
+            let input = MessageInput {
+                content,
+                attachments: attachments.unwrap_or_default(),
+                listing_id,
+            };
+            let reply = match chat::send_message(&conversation_id, user_id, input, client_id.clone()) {
+                Ok(message) => ServerEvent::MessageAck { conversation_id, client_id, message },
+                Err(reason) => {
+                    print(format!("Rejected chat message from {}: {}", user_id, reason));
+                    ServerEvent::MessageNack { conversation_id, client_id, reason }
+                }
+            };
+            Some(reply)
+        },
+        ClientEvent::MarkRead { conversation_id, up_to_seq } => {
+            chat::mark_conversation_as_read(&conversation_id, user_id, up_to_seq).err().map(|e| {
+                print(format!("Rejected read receipt from {}: {}", user_id, e));
+                ServerEvent::rejected(e)
+            })
+        },
+        ClientEvent::SetTyping { conversation_id, is_typing } => {
+            chat::update_typing_status(&conversation_id, user_id, is_typing).err().map(|e| {
+                print(format!("Rejected typing status from {}: {}", user_id, e));
+                ServerEvent::rejected(e)
+            })
+        },
     }
 }
 
 pub fn on_close(args: OnCloseCallbackArgs) {
     let client_principal = args.client_principal;
     let user_id = client_principal.to_string();
-    
+
     print(format!("Client {} disconnected", user_id));
-    
+
     // Remove from connected clients
     CONNECTED_CLIENTS.with(|clients| {
         clients.borrow_mut().remove(&user_id);
     });
+
+    chat::clear_typing_status(&user_id);
 }
 
-fn handle_chat_message(chat_msg: ChatMessage, sender_principal: ClientPrincipal) {
-    let sender_id = sender_principal.to_string();
-    
-    print(format!("Handling chat message from {} in conversation {}", sender_id, chat_msg.conversation_id));
-    
-    // Create the message
-    let message = Message::new(sender_id.clone(), chat_msg.content.clone(), "text".to_string());
-    
-    // Add message to conversation
-    if let Err(e) = add_message_to_conversation(&chat_msg.conversation_id, message.clone()) {
-        print(format!("Failed to add message to conversation: {}", e));
-        return;
-    }
-    
-    // Broadcast message to all participants in the conversation
-    broadcast_to_conversation(&chat_msg.conversation_id, &ChatMessage {
-        message_type: "new_message".to_string(),
-        conversation_id: chat_msg.conversation_id.clone(),
-        sender_id: message.sender_id,
-        content: message.content,
-        timestamp: message.timestamp,
-    }, Some(&sender_id));
-}
-
-fn handle_typing_status(typing_msg: TypingStatusMessage, sender_principal: ClientPrincipal) {
-    let sender_id = sender_principal.to_string();
-    
-    print(format!("Handling typing status from {} in conversation {}: {}", 
-        sender_id, typing_msg.conversation_id, typing_msg.is_typing));
-    
-    // Update typing status in conversation
-    if let Err(e) = crate::chat::update_typing_status(&typing_msg.conversation_id, &sender_id, typing_msg.is_typing) {
-        print(format!("Failed to update typing status: {}", e));
-        return;
+fn send_event(client_principal: ClientPrincipal, event: ServerEvent) -> bool {
+    match ws_send(client_principal, ServerEnvelope::new(event).candid_serialize()) {
+        Ok(()) => true,
+        Err(e) => {
+            print(format!("Failed to send message to {}: {}", client_principal, e));
+            false
+        }
     }
-    
-    // Broadcast typing status to other participants
-    broadcast_to_conversation(&typing_msg.conversation_id, &TypingStatusMessage {
-        message_type: "typing_status".to_string(),
-        conversation_id: typing_msg.conversation_id.clone(),
-        user_id: typing_msg.user_id,
-        is_typing: typing_msg.is_typing,
-    }, Some(&sender_id));
-}
-
-fn broadcast_to_conversation<T>(conversation_id: &str, message: &T, exclude_user: Option<&str>) 
-where 
-    T: CandidType + Clone
-{
+}
+
+fn broadcast_to_conversation(conversation_id: &str, event: ServerEvent, exclude_user: Option<&str>) {
     // Get conversation participants
     let participants = CONVERSATIONS.with(|convs| {
         let convs = convs.borrow();
@@ -178,56 +121,129 @@ where
             Vec::new()
         }
     });
-    
+
     if participants.is_empty() {
         print(format!("No participants found for conversation {}", conversation_id));
         return;
     }
-    
-    // Send message to each connected participant (except sender)
-    CONNECTED_CLIENTS.with(|clients| {
-        let clients = clients.borrow();
-        for participant_id in participants {
-            if exclude_user.map_or(true, |excluded| &participant_id != excluded) {
-                if let Some(client_principal) = clients.get(&participant_id) {
-                    let message_bytes = encode_one(message).unwrap();
-                    match ws_send(*client_principal, message_bytes) {
-                        Ok(()) => {
-                            print(format!("Message sent to {}", participant_id));
-                        },
-                        Err(e) => {
-                            print(format!("Failed to send message to {}: {}", participant_id, e));
-                        }
-                    }
-                } else {
-                    print(format!("Participant {} not connected", participant_id));
-                }
-            }
+
+    // Send the event to each connected participant (except sender)
+    for participant_id in participants {
+        if exclude_user.is_some_and(|excluded| participant_id == excluded) {
+            continue;
         }
-    });
+        if !send_to_user(&participant_id, event.clone()) {
+            print(format!("Participant {} not reached", participant_id));
+        }
+    }
 }
 
 // Public function to broadcast messages from HTTP endpoints
 pub fn broadcast_message_to_conversation(conversation_id: &str, message: &Message) {
-    let chat_msg = ChatMessage {
-        message_type: "new_message".to_string(),
+    broadcast_to_conversation(conversation_id, ServerEvent::NewMessage {
         conversation_id: conversation_id.to_string(),
-        sender_id: message.sender_id.clone(),
-        content: message.content.clone(),
-        timestamp: message.timestamp,
-    };
-    
-    broadcast_to_conversation(conversation_id, &chat_msg, Some(&message.sender_id));
+        message: message.clone(),
+    }, Some(&message.sender_id));
 }
 
 pub fn broadcast_typing_status_to_conversation(conversation_id: &str, user_id: &str, is_typing: bool) {
-    let typing_msg = TypingStatusMessage {
-        message_type: "typing_status".to_string(),
+    broadcast_to_conversation(conversation_id, ServerEvent::TypingStatus {
         conversation_id: conversation_id.to_string(),
         user_id: user_id.to_string(),
         is_typing,
-    };
-    
-    broadcast_to_conversation(conversation_id, &typing_msg, Some(user_id));
+    }, Some(user_id));
 }
 
+pub fn broadcast_read_receipt_to_conversation(conversation_id: &str, user_id: &str, receipt: &ReadReceipt) {
+    broadcast_to_conversation(conversation_id, ServerEvent::ReadReceipt {
+        conversation_id: conversation_id.to_string(),
+        user_id: user_id.to_string(),
+        receipt: receipt.clone(),
+    }, Some(user_id));
+}
+
+pub fn broadcast_offer_to_conversation(offer: &Offer) {
+    broadcast_to_conversation(&offer.conversation_id, ServerEvent::OfferUpdated {
+        conversation_id: offer.conversation_id.clone(),
+        offer: offer.clone(),
+    }, None);
+}
+
+pub fn broadcast_message_edited(conversation_id: &str, message: &Message, editor_id: &str) {
+    broadcast_to_conversation(conversation_id, ServerEvent::MessageEdited {
+        conversation_id: conversation_id.to_string(),
+        message: message.clone(),
+    }, Some(editor_id));
+}
+
+pub fn broadcast_message_deleted(conversation_id: &str, message: &Message, deleted_by: &str) {
+    broadcast_to_conversation(conversation_id, ServerEvent::MessageDeleted {
+        conversation_id: conversation_id.to_string(),
+        message_id: message.id.clone(),
+        seq: message.seq,
+    }, Some(deleted_by));
+}
+
+pub fn broadcast_reactions_updated(conversation_id: &str, message: &Message, user_id: &str) {
+    broadcast_to_conversation(conversation_id, ServerEvent::ReactionsUpdated {
+        conversation_id: conversation_id.to_string(),
+        message_id: message.id.clone(),
+        reactions: message.reactions.clone(),
+    }, Some(user_id));
+}
+
+// Sends an event to a single user if they are connected, returns whether it was delivered
+pub fn send_to_user(user_id: &str, event: ServerEvent) -> bool {
+    let client_principal = CONNECTED_CLIENTS.with(|clients| clients.borrow().get(user_id).cloned());
+
+    match client_principal {
+        Some(client_principal) => send_event(client_principal, event),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::tests::insert_conversation;
+    use crate::protocol::{ErrorCode, PROTOCOL_VERSION};
+    use candid::encode_one;
+
+    fn decode_and_handle(user_id: &str, event: ClientEvent) -> Option<ServerEvent> {
+        let bytes = encode_one(ClientEnvelope { version: PROTOCOL_VERSION, event }).unwrap();
+        let envelope = ClientEnvelope::decode(&bytes).unwrap();
+        handle_event(user_id, envelope.event)
+    }
+
+    #[test]
+    fn non_participants_are_rejected_over_websocket() {
+        let conversation_id = insert_conversation();
+
+        let reply = decode_and_handle("stranger", ClientEvent::SendMessage {
+            conversation_id: conversation_id.clone(),
+            content: "hello".to_string(),
+            client_id: Some("c-1".to_string()),
+            attachments: None,
+            listing_id: None,
+        });
+        match reply {
+            Some(ServerEvent::MessageNack { client_id, reason, .. }) => {
+                assert_eq!(client_id, Some("c-1".to_string()));
+                assert_eq!(reason, "Not a participant in this conversation");
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        for event in [
+            ClientEvent::MarkRead { conversation_id: conversation_id.clone(), up_to_seq: None },
+            ClientEvent::SetTyping { conversation_id: conversation_id.clone(), is_typing: true },
+        ] {
+            match decode_and_handle("stranger", event) {
+                Some(ServerEvent::Error { code, .. }) => assert_eq!(code, ErrorCode::Rejected),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        }
+
+        assert!(CONVERSATIONS.with(|convs| convs.borrow()[&conversation_id].messages.is_empty()));
+    }
+}
@@ -0,0 +1,146 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+
+// Minimal ICRC-1 ledger interface, see https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+// Unit tests run outside a canister where inter-canister calls are unavailable, they use the mock instead
+#[cfg(test)]
+pub use mock::{balance_of, fee, transfer};
+
+#[cfg(not(test))]
+pub async fn balance_of(ledger_id: Principal, account: Account) -> Result<Nat, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger_id, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    Ok(balance)
+}
+
+#[cfg(not(test))]
+pub async fn fee(ledger_id: Principal) -> Result<Nat, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger_id, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    Ok(fee)
+}
+
+#[cfg(not(test))]
+/// Returns the ledger block index of the transfer.
+pub async fn transfer(ledger_id: Principal, arg: TransferArg) -> Result<Nat, String> {
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger_id, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    result.map_err(|e| format!("Ledger transfer failed: {:?}", e))
+}
+
+/// In-memory ICRC-1 ledger for unit tests, holding balances of the test canister and users.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    pub const FEE: u64 = 10_000;
+
+    thread_local! {
+        static BALANCES: RefCell<HashMap<Account, u64>> = RefCell::new(HashMap::new());
+        static BLOCKS: RefCell<u64> = const { RefCell::new(0) };
+        static TRANSFER_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+    }
+
+    /// Stands in for `ic_cdk::id()`, the owner of the escrow subaccounts.
+    pub fn canister_id() -> Principal {
+        Principal::anonymous()
+    }
+
+    pub fn deposit(account: Account, amount: u64) {
+        BALANCES.with(|balances| *balances.borrow_mut().entry(account).or_insert(0) += amount);
+    }
+
+    pub fn balance(account: &Account) -> u64 {
+        BALANCES.with(|balances| balances.borrow().get(account).copied().unwrap_or(0))
+    }
+
+    /// Makes transfers fail with `error` until it is reset with None.
+    pub fn fail_transfers(error: Option<&str>) {
+        TRANSFER_ERROR.with(|e| *e.borrow_mut() = error.map(str::to_string));
+    }
+
+    pub async fn balance_of(_ledger_id: Principal, account: Account) -> Result<Nat, String> {
+        Ok(Nat::from(balance(&account)))
+    }
+
+    pub async fn fee(_ledger_id: Principal) -> Result<Nat, String> {
+        Ok(Nat::from(FEE))
+    }
+
+    pub async fn transfer(_ledger_id: Principal, arg: TransferArg) -> Result<Nat, String> {
+        if let Some(e) = TRANSFER_ERROR.with(|e| e.borrow().clone()) {
+            return Err(e);
+        }
+        if arg.fee.as_ref().is_some_and(|fee| *fee != FEE) {
+            return Err(format!("Ledger transfer failed: {:?}", TransferError::BadFee { expected_fee: Nat::from(FEE) }));
+        }
+
+        let from = Account { owner: canister_id(), subaccount: arg.from_subaccount };
+        let amount = u64::try_from(arg.amount.0).map_err(|_| "Amount is too large.".to_string())?;
+        let available = balance(&from);
+        if available < amount + FEE {
+            return Err(format!("Ledger transfer failed: {:?}", TransferError::InsufficientFunds { balance: Nat::from(available) }));
+        }
+
+        BALANCES.with(|balances| {
+            let mut balances = balances.borrow_mut();
+            *balances.entry(from).or_insert(0) -= amount + FEE;
+            *balances.entry(arg.to).or_insert(0) += amount;
+        });
+        Ok(Nat::from(BLOCKS.with(|blocks| {
+            *blocks.borrow_mut() += 1;
+            *blocks.borrow()
+        })))
+    }
+
+    /// Runs a future to completion, mock ledger calls never suspend so no executor is needed.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let waker = Waker::from(Arc::new(NoopWaker));
+        match pin!(future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Mock ledger calls complete immediately."),
+        }
+    }
+}
@@ -1,20 +1,47 @@
 use std::cell::RefCell;
-use crate::listing::Listing;
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::listing::{ImagesPatch, Listing, ListingPatch, ListingQuery, ListingStatus, NewListing};
+use crate::bulk::{ImportResult, ListingExport};
+use crate::attribute::{AttributeDefinition, ListingAttribute};
+use crate::price::{format_decimal, PriceRange, WholesalePricing};
 use crate::config::Config;
 use crate::user::User;
 use crate::category::Category;
 use crate::review::Review;
-use crate::chat::{Conversation, Message, get_or_create_conversation, add_message_to_conversation, mark_conversation_as_read, update_typing_status};
+use crate::attachment::AttachmentInput;
+use crate::offer::{Offer, OfferResponse, OfferStatus};
+use crate::chat::{ChatSync, Conversation, ConversationSummary, Message, MessageInput, MessagePage, ReadReceipt, get_or_create_conversation, mark_conversation_as_read, update_typing_status};
+use crate::order::{Order, OrderStatus};
+use crate::escrow::{Escrow, PaymentInstructions};
+use crate::revision::{ListingRevision, RevisionHistory};
+use crate::saved_search::SavedSearch;
+use crate::notification::{Notification, NotificationKind, notify};
+use crate::moderation::{AuditEntry, ModerationAction, Report, ReportStatus, ReportTarget, ensure_moderator, ensure_not_banned};
 use candid::{self, CandidType, Deserialize};
 use base64::Engine;
 
 mod category;
 mod listing;
 mod config;
+mod price;
 mod user;
 mod review;
 mod chat;
 mod handlers;
+mod moderation;
+mod notification;
+mod order;
+mod ledger;
+mod escrow;
+mod revision;
+mod bulk;
+mod attribute;
+mod protocol;
+mod attachment;
+mod offer;
+mod saved_search;
+mod clock;
 use crate::handlers::{on_close, on_message, on_open};
 use ic_websocket_cdk::{
     CanisterWsCloseArguments, CanisterWsCloseResult, CanisterWsGetMessagesArguments,
@@ -68,6 +95,10 @@ fn icrc28_trusted_origins() -> Icrc28TrustedOriginsResponse {
 
 // end of code from docs.identitykit
 
+const LISTING_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const LISTING_PURGE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const ESCROW_TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 thread_local! {
     static CONFIG: RefCell<Config> = RefCell::new(Config::new());
     static LISTINGS: RefCell<Vec<Listing>> = RefCell::new(Vec::new());
@@ -84,9 +115,11 @@ fn create_conversation(listing_id: u64) -> Result<Conversation, String> {
     ic_cdk::print(&format!("create_conversation called by {} for listing {}", 
         caller, listing_id));
     
+    ensure_not_banned(&caller)?;
+    
     // Get listing info
     let listing = LISTINGS.with(|listings| {
-        listings.borrow().iter().find(|l| l.id == listing_id).cloned()
+        listings.borrow().iter().find(|l| l.id == listing_id && l.is_public()).cloned()
     });
     
     let listing = match listing {
@@ -111,45 +144,50 @@ fn create_conversation(listing_id: u64) -> Result<Conversation, String> {
 }
 
 #[ic_cdk::update]
-fn send_chat_message(conversation_id: String, content: String) -> Result<Message, String> {
+fn send_chat_message(
+    conversation_id: String,
+    content: String,
+    client_id: Option<String>,
+    attachments: Option<Vec<AttachmentInput>>,
+    listing_id: Option<u64>,
+) -> Result<Message, String> {
     let sender_id = ic_cdk::caller().to_string();
-    
-    // Debug logging
-    ic_cdk::print(&format!("send_chat_message called by {} for conversation {}", sender_id, conversation_id));
-    
-    // Verify conversation exists and user is participant
-    let (is_participant, debug_info) = chat::CONVERSATIONS.with(|convs| {
-        let convs = convs.borrow();
-        
-        // Debug: log all conversation IDs
-        let all_conv_ids: Vec<String> = convs.keys().cloned().collect();
-        ic_cdk::print(&format!("Available conversation IDs: {:?}", all_conv_ids));
-        
-        if let Some(conv) = convs.get(&conversation_id) {
-            let is_participant = conv.participants.contains(&sender_id);
-            ic_cdk::print(&format!("Found conversation. Participants: {:?}, Sender: {}, Is participant: {}", 
-                conv.participants, sender_id, is_participant));
-            (is_participant, format!("Found conversation with participants: {:?}", conv.participants))
-        } else {
-            ic_cdk::print(&format!("Conversation {} not found", conversation_id));
-            (false, "Conversation not found".to_string())
-        }
-    });
-    
-    if !is_participant {
-        return Err(format!("Not a participant in this conversation. Debug: {}", debug_info));
-    }
-    
-    let message = Message::new(sender_id, content, "text".to_string());
-    
-    if let Err(e) = add_message_to_conversation(&conversation_id, message.clone()) {
-        return Err(e);
-    }
-    
-    // Broadcast message via WebSocket
-    handlers::broadcast_message_to_conversation(&conversation_id, &message);
-    
-    Ok(message)
+    let input = MessageInput {
+        content,
+        attachments: attachments.unwrap_or_default(),
+        listing_id,
+    };
+    chat::send_message(&conversation_id, &sender_id, input, client_id)
+}
+
+#[ic_cdk::update]
+fn edit_chat_message(conversation_id: String, message_id: String, content: String) -> Result<Message, String> {
+    let caller = ic_cdk::caller().to_string();
+    chat::edit_message(&conversation_id, &message_id, &caller, content)
+}
+
+#[ic_cdk::update]
+fn delete_chat_message(conversation_id: String, message_id: String) -> Result<Message, String> {
+    let caller = ic_cdk::caller().to_string();
+    chat::delete_message(&conversation_id, &message_id, &caller)
+}
+
+#[ic_cdk::update]
+fn add_message_reaction(conversation_id: String, message_id: String, emoji: String) -> Result<Message, String> {
+    let caller = ic_cdk::caller().to_string();
+    chat::add_reaction(&conversation_id, &message_id, &caller, emoji)
+}
+
+#[ic_cdk::update]
+fn remove_message_reaction(conversation_id: String, message_id: String, emoji: String) -> Result<Message, String> {
+    let caller = ic_cdk::caller().to_string();
+    chat::remove_reaction(&conversation_id, &message_id, &caller, emoji)
+}
+
+#[ic_cdk::query]
+fn get_chat_attachment(conversation_id: String, attachment_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller().to_string();
+    chat::get_attachment(&conversation_id, attachment_id, &caller)
 }
 
 #[ic_cdk::query]
@@ -161,7 +199,7 @@ fn get_conversation_messages(conversation_id: String) -> Result<Vec<Message>, St
         
         if let Some(conversation) = convs.get(&conversation_id) {
             if conversation.participants.contains(&caller) {
-                Ok(conversation.messages.clone())
+                Ok(conversation.messages.iter().filter(|m| !m.hidden).cloned().collect())
             } else {
                 Err("Not a participant in this conversation".to_string())
             }
@@ -172,29 +210,36 @@ fn get_conversation_messages(conversation_id: String) -> Result<Vec<Message>, St
 }
 
 #[ic_cdk::query]
-fn get_user_conversations() -> Vec<Conversation> {
+fn get_conversation_summaries() -> Vec<ConversationSummary> {
+    let caller = ic_cdk::caller().to_string();
+    chat::get_conversation_summaries(&caller)
+}
+
+/// Pages through the history, pass the `seq` of the oldest loaded message as `before_seq` to load older ones.
+#[ic_cdk::query]
+fn get_conversation_history(conversation_id: String, before_seq: Option<u64>, after_seq: Option<u64>, limit: u64) -> Result<MessagePage, String> {
+    let caller = ic_cdk::caller().to_string();
+    chat::get_history(&conversation_id, &caller, before_seq, after_seq, limit)
+}
+
+/// For reconnecting clients, `since` is the `server_time` of the previous sync (0 for a full sync).
+#[ic_cdk::query]
+fn sync_conversations(since: u64) -> ChatSync {
     let caller = ic_cdk::caller().to_string();
-    chat::get_user_conversations(&caller)
+    chat::sync(&caller, since)
 }
 
 #[ic_cdk::update]
-fn mark_conversation_read(conversation_id: String) -> Result<(), String> {
+fn mark_conversation_read(conversation_id: String, up_to_seq: Option<u64>) -> Result<ReadReceipt, String> {
     let caller = ic_cdk::caller().to_string();
-    mark_conversation_as_read(&conversation_id, &caller)
+    mark_conversation_as_read(&conversation_id, &caller, up_to_seq)
 }
 
 #[ic_cdk::update]
 fn set_typing_status(conversation_id: String, is_typing: bool) -> Result<(), String> {
     let caller = ic_cdk::caller().to_string();
     
-    if let Err(e) = update_typing_status(&conversation_id, &caller, is_typing) {
-        return Err(e);
-    }
-    
-    // Broadcast typing status via WebSocket
-    handlers::broadcast_typing_status_to_conversation(&conversation_id, &caller, is_typing);
-    
-    Ok(())
+    update_typing_status(&conversation_id, &caller, is_typing)
 }
 
 #[ic_cdk::update]
@@ -206,25 +251,121 @@ fn add_listing(
     amount: u32,
     images_strings: Vec<String>,
     categories_path: String,
+    currency: Option<String>,
+    wholesale_pricing: Option<WholesalePricing>,
+    attributes: Option<Vec<ListingAttribute>>,
+) -> Result<Listing, String> {
+    create_listing(NewListing {
+        title,
+        description,
+        category,
+        price,
+        currency,
+        wholesale_pricing,
+        amount,
+        images_strings,
+        categories_path,
+        attributes: attributes.unwrap_or_default(),
+        draft: false,
+    })
+}
+
+// Takes the same arguments as add_listing, so both endpoints keep one candid shape
+#[allow(clippy::too_many_arguments)]
+#[ic_cdk::update]
+fn add_listing_draft(
+    title: String,
+    description: String,
+    category: String,
+    price: f64,
+    amount: u32,
+    images_strings: Vec<String>,
+    categories_path: String,
+    currency: Option<String>,
+    wholesale_pricing: Option<WholesalePricing>,
+    attributes: Option<Vec<ListingAttribute>>,
 ) -> Result<Listing, String> {
+    create_listing(NewListing {
+        title,
+        description,
+        category,
+        price,
+        currency,
+        wholesale_pricing,
+        amount,
+        images_strings,
+        categories_path,
+        attributes: attributes.unwrap_or_default(),
+        draft: true,
+    })
+}
+
+/// Creates many listings at once, each item is validated and reported on separately.
+#[ic_cdk::update]
+fn import_listings(items: Vec<NewListing>) -> Result<Vec<ImportResult>, String> {
+    let caller = ic_cdk::caller().to_string();
+    let max_import_batch = CONFIG.with(|config| config.borrow().max_import_batch);
+
+    if !USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
+        return Err("User not found!".to_string());
+    }
+    ensure_not_banned(&caller)?;
+    if items.len() > max_import_batch as usize {
+        return Err(format!("At most {} listings can be imported at once.", max_import_batch));
+    }
+
+    Ok(items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| match create_listing(item) {
+            Ok(listing) => ImportResult { index: index as u32, listing_id: Some(listing.id), error: None },
+            Err(e) => ImportResult { index: index as u32, listing_id: None, error: Some(e) },
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn export_listings() -> Vec<ListingExport> {
+    let caller = ic_cdk::caller().to_string();
+    let config = CONFIG.with(|config| config.borrow().clone());
+
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.owner_id == caller && listing.deleted_at.is_none())
+            .map(|listing| {
+                let images: Vec<String> = listing.images_id.iter().filter_map(|id| load_image(*id)).collect();
+                ListingExport::new(listing, &config, &images)
+            })
+            .collect()
+    })
+}
+
+fn create_listing(new_listing: NewListing) -> Result<Listing, String> {
     let caller = ic_cdk::caller().to_string();
     let owner = USERS.with(|users| {
         users.borrow().iter().find(|user| user.id == caller).cloned()
     });
 
     if let Some(_owner) = owner {
-        let config = CONFIG.with(|config| config.borrow().clone());
-
-        let images_id = IMAGES.with(|images| {
-            let mut images_ref = images.borrow_mut();
-            images_strings.iter().map(|s| {
-                images_ref.push(base64::engine::general_purpose::STANDARD.encode(s));
-                (images_ref.len() - 1) as u64
-            }).collect::<Vec<u64>>()
-        });
+        ensure_not_banned(&caller)?;
 
+        let config = CONFIG.with(|config| config.borrow().clone());
+        let NewListing {
+            title,
+            description,
+            category,
+            price,
+            currency,
+            wholesale_pricing,
+            amount,
+            images_strings,
+            categories_path,
+            attributes,
+            draft,
+        } = new_listing;
 
-        
         if title.len() > config.max_title_len as usize || title.len() < config.min_title_len as usize {
             return Err("Title length is out of range!".to_string());
         }
@@ -232,18 +373,37 @@ fn add_listing(
             return Err("Description length is out of range!".to_string());
         }
 
-        let listing = Listing::new(
+        // Clients send decimal prices, they are stored as fixed-point amounts
+        let price = config.parse_price(price, currency)?;
+        let attributes = config.validate_attributes(&categories_path, attributes)?;
+
+        let mut listing = Listing::new(
             title,
             description,
             category,
             price,
             amount,
             caller,
-            images_id, 
+            Vec::new(),
             categories_path,
         );
+        listing.attributes = attributes;
+        if let Some(pricing) = wholesale_pricing {
+            apply_wholesale_pricing(&config, &mut listing, pricing)?;
+        }
+        if draft {
+            listing.status = ListingStatus::Draft;
+        } else {
+            listing.renew(config.listing_duration_ns());
+        }
+
+        // Images are stored last so rejected listings do not leave orphaned images behind
+        listing.images_id = store_images(&images_strings);
 
         LISTINGS.with(|listings| listings.borrow_mut().push(listing.clone()));
+        if listing.is_public() {
+            saved_search::notify_matching_searches(&listing);
+        }
 
         Ok(listing)
     } else {
@@ -261,17 +421,14 @@ fn edit_listing(
     amount: u32,
     images_strings: Vec<String>, 
     categories_path: String,
+    currency: Option<String>,
+    wholesale_pricing: Option<WholesalePricing>,
+    attributes: Option<Vec<ListingAttribute>>,
 ) -> Result<String, String> {
     let caller = ic_cdk::caller().to_string();
-    let config = CONFIG.with(|config| config.borrow().clone());
+    ensure_not_banned(&caller)?;
 
-    let images_id = IMAGES.with(|images| {
-        let mut images_ref = images.borrow_mut();
-        images_strings.iter().map(|s| {
-            images_ref.push(base64::engine::general_purpose::STANDARD.encode(s));
-            (images_ref.len() - 1) as u64
-        }).collect::<Vec<u64>>()
-    });
+    let config = CONFIG.with(|config| config.borrow().clone());
 
     if title.len() > config.max_title_len as usize || title.len() < config.min_title_len as usize {
         return Err("Title length is invalid!".to_string());
@@ -280,21 +437,54 @@ fn edit_listing(
         return Err("Description length is invalid!".to_string());
     }
 
+    let price = config.parse_price(price, currency)?;
+
     LISTINGS.with(|listings| {
         let mut listings = listings.borrow_mut();
 
-        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == id) {
+        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == id && listing.deleted_at.is_none()) {
             if listing.owner_id != caller {
                 return Err("Permission denied: You are not the owner of this listing.".to_string());
             }
 
+            let before = listing.clone();
+
+            // Validate the new terms on a copy so a rejected edit leaves the listing untouched
+            let mut updated = listing.clone();
+            let currency_changed = price.currency != listing.price.currency;
+            updated.price = price;
+            // Omitted pricing keeps the current terms, re-validated against the new base price
+            let result = match wholesale_pricing {
+                Some(pricing) => apply_wholesale_pricing(&config, &mut updated, pricing),
+                None if currency_changed && !listing.price_tiers.is_empty() => {
+                    Err("Price tiers must be resent when changing the currency.".to_string())
+                }
+                None => updated.set_wholesale_pricing(listing.min_order_quantity, listing.price_tiers.clone()),
+            };
+            result?;
+            // Kept attributes must still fit the schema of the new category
+            let attributes = attributes.unwrap_or_else(|| listing.attributes.clone());
+            let attributes = match config.validate_attributes(&categories_path, attributes) {
+                Ok(attributes) => attributes,
+                Err(e) => return Err(e),
+            };
+
             listing.title = title;
             listing.description = description;
             listing.category = category;
-            listing.price = price;
-            listing.amount = amount;
+            listing.price = updated.price;
+            listing.min_order_quantity = updated.min_order_quantity;
+            listing.price_tiers = updated.price_tiers;
+            listing.set_amount(amount);
             listing.categories_path = categories_path;
-            listing.images_id = images_id;
+            listing.attributes = attributes;
+            // Resending the same images keeps their ids, so the edit is not recorded as an image change
+            if !images_match(&listing.images_id, &images_strings) {
+                release_images(&listing.images_id);
+                listing.images_id = store_images(&images_strings);
+            }
+
+            revision::record(&before, listing, &caller, &config.currencies, ic_cdk::api::time());
 
             Ok("Listing successfully updated!".to_string())
         } else {
@@ -302,366 +492,1587 @@ fn edit_listing(
         }
     })
 }
-#[ic_cdk::query]
-fn get_image_by_id(image_id: u64) -> Option<String> {
-    IMAGES.with(|images| {
-        images.borrow().get(image_id as usize).cloned()
-    })
-}
+#[ic_cdk::update]
+fn update_listing(id: u64, patch: ListingPatch) -> Result<Listing, String> {
+    let caller = ic_cdk::caller().to_string();
+    ensure_not_banned(&caller)?;
 
+    let config = CONFIG.with(|config| config.borrow().clone());
 
-#[ic_cdk::query]
-fn get_listings_by_category(category: String) -> Vec<Listing> {
-    LISTINGS.with(|listings| {
-        listings
-            .borrow()
-            .iter()
-            .filter(|listing| listing.category == category)
-            .cloned() // <-- Important: clones each Listing
-            .collect()
-    })
-}
+    let mut listing = match LISTINGS.with(|listings| listings.borrow().iter().find(|listing| listing.id == id && listing.deleted_at.is_none()).cloned()) {
+        Some(listing) => listing,
+        None => return Err("Listing not found!".to_string()),
+    };
+    if listing.owner_id != caller {
+        return Err("Permission denied: You are not the owner of this listing.".to_string());
+    }
+    let before = listing.clone();
 
+    if let Some(title) = patch.title {
+        if title.len() > config.max_title_len as usize || title.len() < config.min_title_len as usize {
+            return Err("Title length is invalid!".to_string());
+        }
+        listing.title = title;
+    }
+    if let Some(description) = patch.description {
+        if description.len() > config.max_description_len as usize || description.len() < config.min_description_len as usize {
+            return Err("Description length is invalid!".to_string());
+        }
+        listing.description = description;
+    }
+    if let Some(category) = patch.category {
+        listing.category = category;
+    }
+    if let Some(categories_path) = patch.categories_path {
+        listing.categories_path = categories_path;
+    }
 
-#[ic_cdk::query]
-fn get_listings_by_id(ids: Vec<u64>) -> Vec<Listing> {
-    LISTINGS.with(|listings| {
-        listings
-            .borrow()
-            .iter()
-            .filter(|listing| ids.contains(&listing.id))
-            .cloned()
-            .collect()
-    })
-}
+    let category_changed = listing.categories_path != before.categories_path;
+    match patch.attributes {
+        Some(attributes) => listing.attributes = config.validate_attributes(&listing.categories_path, attributes)?,
+        None if category_changed => {
+            let attributes = listing.attributes.clone();
+            listing.attributes = config.validate_attributes(&listing.categories_path, attributes)?;
+        }
+        None => {}
+    }
 
+    let currency_changed = patch.currency.as_ref().is_some_and(|currency| *currency != listing.price.currency);
+    match (patch.price, patch.currency) {
+        (Some(price), currency) => {
+            let currency = currency.unwrap_or_else(|| listing.price.currency.clone());
+            listing.price = config.parse_price(price, Some(currency))?;
+        }
+        (None, Some(_)) if currency_changed => {
+            return Err("Changing the currency requires a new price.".to_string());
+        }
+        (None, _) => {}
+    }
 
-#[ic_cdk::update]
-fn delete_listing(id: u64) -> Option<String> {
-    let caller = ic_cdk::caller().to_string();
+    // Tiers are re-validated against the (possibly new) base price
+    match patch.wholesale_pricing {
+        Some(pricing) => apply_wholesale_pricing(&config, &mut listing, pricing)?,
+        None if currency_changed && !listing.price_tiers.is_empty() => {
+            return Err("Price tiers must be resent when changing the currency.".to_string());
+        }
+        None => {
+            let min_order_quantity = listing.min_order_quantity;
+            let price_tiers = listing.price_tiers.clone();
+            listing.set_wholesale_pricing(min_order_quantity, price_tiers)?;
+        }
+    }
 
-    LISTINGS.with(|listings| {
-        let mut listings = listings.borrow_mut();
+    let removed_images = match patch.images {
+        Some(images) => apply_images_patch(&mut listing, &images)?,
+        None => Vec::new(),
+    };
 
-        if let Some(index) = listings.iter().position(|listing| listing.id == id) {
-            if listings[index].owner_id != caller {
-                return Some("Permission denied: You are not the owner of this listing.".to_string());
-            }
+    if let Some(amount) = patch.amount {
+        listing.set_amount(amount);
+    }
 
-            listings.remove(index);
-            None
-        } else {
-            Some("Listing not found.".to_string())
+    LISTINGS.with(|listings| {
+        if let Some(stored) = listings.borrow_mut().iter_mut().find(|stored| stored.id == id) {
+            *stored = listing.clone();
         }
-    })
-}
+    });
+    release_images(&removed_images);
+    revision::record(&before, &listing, &caller, &config.currencies, ic_cdk::api::time());
 
-#[ic_cdk::query]
-fn get_listings() -> Vec<Listing> {
-    LISTINGS.with(|listings| listings.borrow().clone())
+    Ok(listing)
 }
 
+/// Edit history of a listing, visible to its owner, moderators and anyone who chatted about it.
 #[ic_cdk::query]
-fn get_listing_by_id(id: u64) -> Option<Listing> {
-    LISTINGS.with(|listings| {
-        listings
-            .borrow()
-            .iter()
-            .find(|listing| listing.id == id)
-            .cloned()
-    })
-}
+fn get_listing_revisions(listing_id: u64) -> Result<Vec<ListingRevision>, String> {
+    let caller = ic_cdk::caller().to_string();
 
-#[ic_cdk::query]
-fn get_config() -> Config {
-    CONFIG.with(|config| config.borrow().clone())
-}
+    // The history outlives the listing, so purged listings are looked up there
+    let owner_id = LISTINGS.with(|listings| {
+        listings.borrow().iter().find(|listing| listing.id == listing_id).map(|listing| listing.owner_id.clone())
+    });
+    let owner_id = match owner_id.or_else(|| revision::get_owner(listing_id)) {
+        Some(owner_id) => owner_id,
+        None => return Err("Listing not found!".to_string()),
+    };
 
-#[ic_cdk::query]
-fn get_categories() -> Vec<Category> {
-    let config = CONFIG.with(|config| config.borrow().clone());
-    config.categories
+    let allowed = owner_id == caller
+        || moderation::is_moderator(&caller)
+        || chat::has_conversation_about(listing_id, &caller);
+    if !allowed {
+        return Err("Permission denied: You cannot view the history of this listing.".to_string());
+    }
+
+    Ok(revision::get_revisions(listing_id))
 }
 
-#[ic_cdk::update]
-fn add_user(name: String, email: String, phone_number: String, company_name: String) -> Result<User, String> {
-    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
-    
-    if USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
-        return Err("User already exists!".to_string());
+// Returns the kept image ids in their new order, and the removed ones
+fn patch_image_ids(current: &[u64], patch: &ImagesPatch) -> Result<(Vec<u64>, Vec<u64>), String> {
+    let mut removed: Vec<u64> = Vec::new();
+    for id in &patch.remove {
+        if !current.contains(id) {
+            return Err(format!("Image {} does not belong to this listing.", id));
+        }
+        if !removed.contains(id) {
+            removed.push(*id);
+        }
     }
 
-    let user = User::new(caller, name.clone(), email.clone(), phone_number.clone(), company_name.clone());
-    USERS.with(|users| users.borrow_mut().push(user.clone()));
+    let kept: Vec<u64> = current.iter().filter(|id| !removed.contains(*id)).cloned().collect();
 
-    Ok(user)
+    let order = match &patch.order {
+        Some(order) => order,
+        None => return Ok((kept, removed)),
+    };
+    for (index, id) in order.iter().enumerate() {
+        if !kept.contains(id) {
+            return Err(format!("Image {} in the new order is not kept by this listing.", id));
+        }
+        if order[..index].contains(id) {
+            return Err(format!("Image {} is listed more than once in the new order.", id));
+        }
+    }
+    if let Some(id) = kept.iter().find(|id| !order.contains(*id)) {
+        return Err(format!("Image {} is missing from the new order.", id));
+    }
+
+    Ok((order.clone(), removed))
+}
+
+// Kept images come first in their new order, followed by the added ones.
+// Returns the removed ids, to be released once the listing is saved.
+fn apply_images_patch(listing: &mut Listing, patch: &ImagesPatch) -> Result<Vec<u64>, String> {
+    let (mut images_id, removed) = patch_image_ids(&listing.images_id, patch)?;
+    // New images are only stored once the whole patch is known to be valid
+    images_id.extend(store_images(&patch.add));
+    listing.images_id = images_id;
+    Ok(removed)
+}
+
+fn apply_wholesale_pricing(config: &Config, listing: &mut Listing, pricing: WholesalePricing) -> Result<(), String> {
+    let tiers = config.parse_price_tiers(pricing.price_tiers, &listing.price.currency)?;
+    listing.set_wholesale_pricing(pricing.min_order_quantity, tiers)
 }
 
 #[ic_cdk::update]
-fn add_empty_user() -> Result<User, String> {
-    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+fn publish_listing(id: u64) -> Result<Listing, String> {
+    change_listing_status(id, ListingStatus::Active, Some(ListingStatus::Draft))?;
+    renew_listing_expiry(id);
 
-    if USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
-        return Err("User already exists!".to_string());
+    let listing = get_listing(id).ok_or("Listing not found!".to_string())?;
+    if listing.is_public() {
+        saved_search::notify_matching_searches(&listing);
     }
-
-    let user = User::new(caller, "".to_string(), "".to_string(), "".to_string(), "".to_string());
-    USERS.with(|users| users.borrow_mut().push(user.clone()));
-    Ok(user)
+    Ok(listing)
 }
 
-
-#[ic_cdk::query]
-fn get_users() -> Vec<User> {
-    USERS.with(|users| users.borrow().clone())
+#[ic_cdk::update]
+fn pause_listing(id: u64) -> Result<Listing, String> {
+    change_listing_status(id, ListingStatus::Paused, None)
 }
 
-#[ic_cdk::query]
-fn get_user_by_principal(principal: String) -> Option<Vec<u8>> {
-    USERS.with(|users| {
-        users.borrow().iter().find(|user| user.id == principal).map(|user| {
-            let mut value = serde_json::to_value(user).unwrap();
-            if let serde_json::Value::Object(ref mut map) = value {
-                map.remove("favorites_id");
-            }
-            serde_json::to_vec(&value).unwrap()
-        })
-    })
+#[ic_cdk::update]
+fn resume_listing(id: u64) -> Result<Listing, String> {
+    change_listing_status(id, ListingStatus::Active, Some(ListingStatus::Paused))
 }
 
-#[ic_cdk::query]
-fn get_active_user() -> Option<User> {
-    let caller = ic_cdk::caller().to_string();
-    USERS.with(|users| {
-        users.borrow().iter().find(|user| user.id == caller).cloned()
-    })
+#[ic_cdk::update]
+fn archive_listing(id: u64) -> Result<Listing, String> {
+    change_listing_status(id, ListingStatus::Archived, None)
 }
 
 #[ic_cdk::update]
-fn edit_active_user(name: String, email: String, phone_number: String, company_name: String) -> Option<String> {
+fn renew_listing(id: u64) -> Result<Listing, String> {
+    let caller = ic_cdk::caller().to_string();
+    let duration = CONFIG.with(|config| config.borrow().listing_duration_ns());
+
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+
+        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == id && listing.deleted_at.is_none()) {
+            if listing.owner_id != caller {
+                return Err("Permission denied: You are not the owner of this listing.".to_string());
+            }
+
+            match listing.status {
+                ListingStatus::Draft | ListingStatus::Archived => {
+                    return Err(format!("Cannot renew a listing in {:?} state.", listing.status));
+                }
+                ListingStatus::Expired => listing.transition_to(ListingStatus::Active)?,
+                _ => {}
+            }
+
+            listing.renew(duration);
+            Ok(listing.clone())
+        } else {
+            Err("Listing not found!".to_string())
+        }
+    })
+}
+
+fn renew_listing_expiry(id: u64) {
+    let duration = CONFIG.with(|config| config.borrow().listing_duration_ns());
+
+    LISTINGS.with(|listings| {
+        if let Some(listing) = listings.borrow_mut().iter_mut().find(|listing| listing.id == id) {
+            listing.renew(duration);
+        }
+    });
+}
+
+fn get_listing(id: u64) -> Option<Listing> {
+    LISTINGS.with(|listings| listings.borrow().iter().find(|listing| listing.id == id).cloned())
+}
+
+// Periodic job moving listings past their expiry date to the expired state
+fn expire_listings() {
+    let now = ic_cdk::api::time();
+
+    let expired: Vec<(String, u64, String)> = LISTINGS.with(|listings| {
+        listings
+            .borrow_mut()
+            .iter_mut()
+            .filter(|listing| listing.can_expire_at(now))
+            .filter_map(|listing| {
+                listing.transition_to(ListingStatus::Expired).ok()?;
+                Some((listing.owner_id.clone(), listing.id, listing.title.clone()))
+            })
+            .collect()
+    });
+
+    for (owner_id, listing_id, listing_title) in expired {
+        notify(&owner_id, NotificationKind::ListingExpired { listing_id, listing_title });
+    }
+}
+
+// `from` restricts the transition to listings currently in that state
+fn change_listing_status(id: u64, next: ListingStatus, from: Option<ListingStatus>) -> Result<Listing, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+
+        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == id && listing.deleted_at.is_none()) {
+            if listing.owner_id != caller {
+                return Err("Permission denied: You are not the owner of this listing.".to_string());
+            }
+            if let Some(from) = from {
+                if listing.status != from {
+                    return Err(format!("Listing is not in {:?} state.", from));
+                }
+            }
+
+            listing.transition_to(next)?;
+            Ok(listing.clone())
+        } else {
+            Err("Listing not found!".to_string())
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_image_by_id(image_id: u64) -> Option<String> {
+    // Chat attachments share the storage but are only served to conversation participants
+    if attachment::attachment_conversation(image_id).is_some() {
+        return None;
+    }
+    load_image(image_id)
+}
+
+fn load_image(image_id: u64) -> Option<String> {
+    IMAGES.with(|images| {
+        images.borrow().get(image_id as usize).filter(|image| !image.is_empty()).cloned()
+    })
+}
+
+fn store_images(images_strings: &[String]) -> Vec<u64> {
+    IMAGES.with(|images| {
+        let mut images_ref = images.borrow_mut();
+        images_strings.iter().map(|s| {
+            images_ref.push(base64::engine::general_purpose::STANDARD.encode(s));
+            (images_ref.len() - 1) as u64
+        }).collect::<Vec<u64>>()
+    })
+}
+
+// True if `images_strings` are the images already stored under `image_ids`, in the same order
+fn images_match(image_ids: &[u64], images_strings: &[String]) -> bool {
+    image_ids.len() == images_strings.len()
+        && image_ids.iter().zip(images_strings).all(|(id, image)| {
+            load_image(*id).is_some_and(|stored| stored == base64::engine::general_purpose::STANDARD.encode(image))
+        })
+}
+
+// Image ids are positions in IMAGES, so removed images are emptied instead of shifting the vector
+fn release_images(image_ids: &[u64]) {
+    IMAGES.with(|images| {
+        let mut images = images.borrow_mut();
+        for id in image_ids {
+            if let Some(image) = images.get_mut(*id as usize) {
+                image.clear();
+            }
+        }
+    });
+}
+
+
+#[ic_cdk::query]
+fn get_listings_by_category(category: String) -> Vec<Listing> {
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.category == category && listing.is_public())
+            .cloned() // <-- Important: clones each Listing
+            .collect()
+    })
+}
+
+
+#[ic_cdk::query]
+fn search_listings(query: ListingQuery) -> Result<Vec<Listing>, String> {
+    if let Some(range) = &query.price_range {
+        range.validate()?;
+    }
+    for filter in query.attributes.iter().flatten() {
+        filter.validate()?;
+    }
+
+    let mut results: Vec<Listing> = LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.is_public() && query.matches(listing))
+            .cloned()
+            .collect()
+    });
+    query.sort(&mut results);
+
+    Ok(results)
+}
+
+#[ic_cdk::query]
+fn get_listings_by_id(ids: Vec<u64>) -> Vec<Listing> {
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| ids.contains(&listing.id) && listing.is_public())
+            .cloned()
+            .collect()
+    })
+}
+
+
+#[ic_cdk::update]
+fn delete_listing(id: u64) -> Option<String> {
+    let caller = ic_cdk::caller().to_string();
+
+    // The listing is only hidden here, purge_deleted_listings removes it once the restore window ends
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+
+        match listings.iter_mut().find(|listing| listing.id == id && listing.deleted_at.is_none()) {
+            Some(listing) if listing.owner_id != caller => {
+                Some("Permission denied: You are not the owner of this listing.".to_string())
+            }
+            Some(listing) => {
+                listing.deleted_at = Some(ic_cdk::api::time());
+                None
+            }
+            None => Some("Listing not found.".to_string()),
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn restore_listing(id: u64) -> Result<Listing, String> {
+    let caller = ic_cdk::caller().to_string();
+    let window = CONFIG.with(|config| config.borrow().listing_restore_window_ns());
+    let now = ic_cdk::api::time();
+
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+
+        match listings.iter_mut().find(|listing| listing.id == id && listing.deleted_at.is_some()) {
+            Some(listing) if listing.owner_id != caller => {
+                Err("Permission denied: You are not the owner of this listing.".to_string())
+            }
+            Some(listing) if listing.deleted_at.is_some_and(|deleted_at| deleted_at + window <= now) => {
+                Err("The restore window for this listing has ended.".to_string())
+            }
+            Some(listing) => {
+                listing.deleted_at = None;
+                Ok(listing.clone())
+            }
+            None => Err("Listing not found.".to_string()),
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_deleted_listings() -> Vec<Listing> {
+    let caller = ic_cdk::caller().to_string();
+
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.owner_id == caller && listing.deleted_at.is_some())
+            .cloned()
+            .collect()
+    })
+}
+
+// Periodic job purging soft-deleted listings whose restore window has ended
+fn purge_deleted_listings() {
+    let now = ic_cdk::api::time();
+    let window = CONFIG.with(|config| config.borrow().listing_restore_window_ns());
+
+    let expired: Vec<(u64, String)> = LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.deleted_at.is_some_and(|deleted_at| deleted_at + window <= now))
+            .map(|listing| (listing.id, listing.owner_id.clone()))
+            .collect()
+    });
+
+    for (id, owner_id) in expired {
+        if let Err(e) = remove_listing(id, &owner_id) {
+            ic_cdk::print(format!("Failed to purge listing {}: {}", id, e));
+        }
+    }
+}
+
+// Permanently removes a listing with its images, favorites and reports.
+// Used by the purge job and moderator deletions.
+fn remove_listing(id: u64, removed_by: &str) -> Result<Listing, String> {
+    let removed = LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        listings
+            .iter()
+            .position(|listing| listing.id == id)
+            .map(|index| listings.remove(index))
+    });
+
+    match removed {
+        Some(listing) => {
+            release_images(&listing.images_id);
+
+            USERS.with(|users| {
+                for user in users.borrow_mut().iter_mut() {
+                    user.remove_favorite(id);
+                }
+            });
+
+            moderation::close_reports_for_target(&ReportTarget::Listing(id), removed_by, ModerationAction::Delete);
+            if let Some(reviews) = &listing.reviews {
+                for review in reviews {
+                    let target = ReportTarget::Review { listing_id: id, owner_id: review.owner_id.clone() };
+                    moderation::close_reports_for_target(&target, removed_by, ModerationAction::Delete);
+                }
+            }
+            Ok(listing)
+        }
+        None => Err("Listing not found.".to_string()),
+    }
+}
+
+#[ic_cdk::query]
+fn get_listings() -> Vec<Listing> {
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.is_public())
+            .cloned()
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_listing_by_id(id: u64) -> Option<Listing> {
+    let caller = ic_cdk::caller().to_string();
+
+    // Owners can still open their own paused, sold out, archived or deleted listings,
+    // and chat participants can resolve the listing their conversation is about
+    LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .find(|listing| {
+                listing.id == id
+                    && (listing.is_public()
+                        || (!listing.hidden && (listing.owner_id == caller || chat::has_conversation_about(id, &caller))))
+            })
+            .cloned()
+    })
+}
+
+#[ic_cdk::query]
+fn get_config() -> Config {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+#[ic_cdk::query]
+fn get_categories() -> Vec<Category> {
+    let config = CONFIG.with(|config| config.borrow().clone());
+    config.categories
+}
+
+/// Attributes listings in the category can have, including the ones inherited from parent categories.
+#[ic_cdk::query]
+fn get_attribute_schema(categories_path: String) -> Vec<AttributeDefinition> {
+    CONFIG.with(|config| config.borrow().attribute_definitions(&categories_path))
+}
+
+#[ic_cdk::update]
+fn add_user(name: String, email: String, phone_number: String, company_name: String) -> Result<User, String> {
+    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+    
+    if USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
+        return Err("User already exists!".to_string());
+    }
+
+    let user = User::new(caller, name.clone(), email.clone(), phone_number.clone(), company_name.clone());
+    USERS.with(|users| users.borrow_mut().push(user.clone()));
+
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn add_empty_user() -> Result<User, String> {
+    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+
+    if USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
+        return Err("User already exists!".to_string());
+    }
+
+    let user = User::new(caller, "".to_string(), "".to_string(), "".to_string(), "".to_string());
+    USERS.with(|users| users.borrow_mut().push(user.clone()));
+    Ok(user)
+}
+
+
+#[ic_cdk::query]
+fn get_users() -> Vec<User> {
+    USERS.with(|users| users.borrow().clone())
+}
+
+#[ic_cdk::query]
+fn get_user_by_principal(principal: String) -> Option<Vec<u8>> {
+    USERS.with(|users| {
+        users.borrow().iter().find(|user| user.id == principal).map(|user| {
+            let mut value = serde_json::to_value(user).unwrap();
+            if let serde_json::Value::Object(ref mut map) = value {
+                map.remove("favorites_id");
+            }
+            serde_json::to_vec(&value).unwrap()
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_active_user() -> Option<User> {
+    let caller = ic_cdk::caller().to_string();
+    USERS.with(|users| {
+        users.borrow().iter().find(|user| user.id == caller).cloned()
+    })
+}
+
+#[ic_cdk::update]
+fn edit_active_user(name: String, email: String, phone_number: String, company_name: String) -> Option<String> {
+    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        if let Some(user) = users.iter_mut().find(|user| user.id == caller) {
+            user.name = name;
+            user.email = email;
+            user.phone_number = phone_number;
+            user.company_name = company_name;
+            return None;
+        }
+
+        Some("User not found!".to_string())
+    })
+}
+
+
+#[ic_cdk::update]
+fn add_favorite_listing(listing_id: u64) {
+    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+
+    if let Err(e) = set_favorite(&caller, listing_id, true) {
+        ic_cdk::print(format!("add_favorite_listing failed for {}: {}", caller, e));
+    }
+}
+
+#[ic_cdk::update]
+fn remove_favorite_listing(listing_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller().to_string();
+    set_favorite(&caller, listing_id, false).map(|_| ())
+}
+
+#[ic_cdk::update]
+fn toggle_favorite_listing(listing_id: u64) -> Result<bool, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    let is_favorite = USERS.with(|users| {
+        users.borrow().iter().find(|user| user.id == caller).map(|user| user.is_favorite(listing_id))
+    });
+
+    match is_favorite {
+        Some(is_favorite) => set_favorite(&caller, listing_id, !is_favorite).map(|_| !is_favorite),
+        None => Err("User not found!".to_string()),
+    }
+}
+
+// Adds or removes a favorite and keeps Listing.favorites_count in sync.
+// Returns whether the user's favorites actually changed.
+fn set_favorite(caller: &str, listing_id: u64, favorite: bool) -> Result<bool, String> {
+    let owner_id = LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .find(|listing| listing.id == listing_id && listing.deleted_at.is_none())
+            .map(|listing| listing.owner_id.clone())
+    });
+
+    match &owner_id {
+        Some(owner_id) if favorite && owner_id == caller => {
+            return Err("You cannot favorite your own listing.".to_string());
+        }
+        None if favorite => return Err("Listing not found!".to_string()),
+        _ => {}
+    }
+
+    let changed = USERS.with(|users| {
+        let mut users = users.borrow_mut();
+
+        match users.iter_mut().find(|user| user.id == caller) {
+            Some(user) if favorite => Ok(user.add_favorite(listing_id)),
+            Some(user) => Ok(user.remove_favorite(listing_id)),
+            None => Err("User not found!".to_string()),
+        }
+    });
+
+    if let (Ok(true), true, Some(owner_id)) = (&changed, favorite, &owner_id) {
+        notify(owner_id, NotificationKind::ListingFavorited {
+            listing_id,
+            user_id: caller.to_string(),
+        });
+    }
+
+    if let Ok(true) = changed {
+        LISTINGS.with(|listings| {
+            if let Some(listing) = listings.borrow_mut().iter_mut().find(|listing| listing.id == listing_id) {
+                if favorite {
+                    listing.favorites_count += 1;
+                } else {
+                    listing.favorites_count = listing.favorites_count.saturating_sub(1);
+                }
+            }
+        });
+    }
+
+    changed
+}
+
+#[ic_cdk::query]
+fn get_active_user_favorite_listings() -> Vec<Listing> {
+    let user = get_active_user();
+    let mut favorites = Vec::new();
+    if let Some(user) = user {
+        if let Some(favorites_id) = user.favorites_id {
+            LISTINGS.with(|listings| {
+                for listing_id in favorites_id {
+                    if let Some(listing) = listings.borrow().iter().find(|listing| listing.id == listing_id && listing.is_public()) {
+                        favorites.push(listing.clone());
+                    }
+                }
+            });
+        }
+    }
+    favorites
+}
+
+#[ic_cdk::query]
+fn get_listings_by_active_user() -> Result<Vec<Listing>, String> {
     let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
 
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
+    let owner = USERS.with(|users| {
+        users.borrow().iter().find(|user| user.id == caller).cloned()
+    });
+
+    // If user doesn't exist yet (e.g., during user creation), return empty array instead of error
+    if owner.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let user_listings = LISTINGS.with(|listings| {
+        listings
+            .borrow()
+            .iter()
+            .filter(|listing| listing.owner_id == caller && listing.deleted_at.is_none())
+            .cloned()
+            .collect()
+    });
+    Ok(user_listings)
+}
+
+#[ic_cdk::query]
+fn get_reviews_of_listing(listing_id: u64) -> Result<Vec<Review>, String> {
+    LISTINGS.with(|listings| {
+        let listings = listings.borrow();
+        
+        // Reviews of moderated or deleted listings are withheld with the listing
+        if let Some(listing) = listings.iter().find(|listing| listing.id == listing_id && !listing.hidden && listing.deleted_at.is_none()) {
+            if let Some(reviews) = &listing.reviews {
+                Ok(reviews.iter().filter(|review| !review.hidden).cloned().collect())
+            } else {
+                Err("No reviews for this listing.".to_string())
+            }
+        } else {
+            Err("Listing not found.".to_string())
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn calculate_average_rating_of_listing(listing_id: u64) -> Result<f64, String> {
+    LISTINGS.with(|listings| {
+        let listings = listings.borrow();
+
+        if let Some(listing) = listings.iter().find(|listing| listing.id == listing_id && !listing.hidden && listing.deleted_at.is_none()) {
+            if let Some(reviews) = &listing.reviews {
+                let reviews: Vec<&Review> = reviews.iter().filter(|review| !review.hidden).collect();
+                if reviews.is_empty() {
+                    return Err("No reviews for this listing.".to_string());
+                }
+
+                let total_rating: u32 = reviews.iter().map(|review| review.rating as u32).sum();
+                let average_rating = total_rating as f64 / reviews.len() as f64;
+
+                Ok(average_rating)
+            } else {
+                Err("No reviews for this listing.".to_string())
+            }
+        } else {
+            Err("Listing not found.".to_string())
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn add_review(listing_id: u64, rating: u8, comment: String) -> Result<Review, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    // Walidacja oceny
+    if rating > 5 {
+        return Err("Rating must be between 0 and 5.".to_string());
+    }
+
+    // Sprawdzenie, czy użytkownik istnieje
+    let user_exists = USERS.with(|users| users.borrow().iter().any(|user| user.id == caller));
+    if !user_exists {
+        return Err("User not found.".to_string());
+    }
+
+    ensure_not_banned(&caller)?;
+
+    // Operacje na liście ogłoszeń
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+
+        // Znajdź ogłoszenie
+        let listing = listings.iter_mut().find(|listing| listing.id == listing_id);
+        if let Some(listing) = listing {
+            // Sprawdzenie, czy użytkownik nie jest właścicielem ogłoszenia
+            if listing.owner_id == caller {
+                return Err("You cannot review your own listing.".to_string());
+            }
+
+            // Sprawdzenie, czy użytkownik już dodał opinię
+            if let Some(reviews) = &listing.reviews {
+                if reviews.iter().any(|review| review.owner_id == caller) {
+                    return Err("You have already added a review for this listing.".to_string());
+                }
+            }
 
-        if let Some(user) = users.iter_mut().find(|user| user.id == caller) {
-            user.name = name;
-            user.email = email;
-            user.phone_number = phone_number;
-            user.company_name = company_name;
-            return None;
+            // Dodanie nowej opinii
+            let review = Review::new(caller, rating, comment);
+            if let Some(reviews) = &mut listing.reviews {
+                reviews.push(review.clone());
+            } else {
+                listing.reviews = Some(vec![review.clone()]);
+            }
+
+            notify(&listing.owner_id, NotificationKind::NewReview {
+                listing_id,
+                reviewer_id: review.owner_id.clone(),
+                rating,
+            });
+            return Ok(review); // Zwrócenie nowej opinii
+        }
+    Err("Listing not found.".to_string()) // If the listing does not exist
+    })
+}
+
+
+#[ic_cdk::update]
+fn edit_review(listing_id: u64, rating: u8, comment: String) -> Option<String> {
+    let caller = ic_cdk::caller().to_string();
+
+    if rating > 5 {
+        return Some("Rating must be between 0 and 5.".to_string());
+    }
+
+    let owner = USERS.with(|users| {
+        users.borrow().iter().find(|user| user.id == caller).cloned()
+    });
+
+    if owner.is_none() {
+        return Some("User not found!".to_string());
+    }
+
+    if let Err(e) = ensure_not_banned(&caller) {
+        return Some(e);
+    }
+
+    LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == listing_id) {
+            if let Some(ref mut reviews) = listing.reviews {
+                if let Some(review) = reviews.iter_mut().find(|review| review.owner_id == caller) {
+                    review.rating = rating;
+                    review.comment = comment;
+                    return None;
+                }
+            }
+            Some("Review not found.".to_string())
+        } else {
+            Some("Listing not found.".to_string())
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn delete_review(listing_id: u64) -> Option<String> {
+    let caller = ic_cdk::caller().to_string();
+    remove_review(listing_id, &caller, &caller).err()
+}
+
+// Shared by delete_review and moderator deletions
+fn remove_review(listing_id: u64, owner_id: &str, removed_by: &str) -> Result<Review, String> {
+    let removed = LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == listing_id) {
+            if let Some(ref mut reviews) = listing.reviews {
+                if let Some(index) = reviews.iter().position(|review| review.owner_id == owner_id) {
+                    return Ok(reviews.remove(index));
+                }
+            }
+            Err("Review not found.".to_string())
+        } else {
+            Err("Listing not found.".to_string())
+        }
+    });
+
+    if removed.is_ok() {
+        let target = ReportTarget::Review { listing_id, owner_id: owner_id.to_string() };
+        moderation::close_reports_for_target(&target, removed_by, ModerationAction::Delete);
+    }
+    removed
+}
+
+// Order API endpoints
+#[ic_cdk::update]
+fn place_order(listing_id: u64, quantity: u32) -> Result<Order, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    if !USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
+        return Err("User not found!".to_string());
+    }
+    ensure_not_banned(&caller)?;
+    if quantity == 0 {
+        return Err("Quantity must be greater than 0.".to_string());
+    }
+
+    let listing = LISTINGS.with(|listings| {
+        listings.borrow().iter().find(|listing| listing.id == listing_id && listing.is_public()).cloned()
+    });
+    let listing = match listing {
+        Some(listing) => listing,
+        None => return Err("Listing not found.".to_string()),
+    };
+
+    if listing.owner_id == caller {
+        return Err("You cannot order from your own listing.".to_string());
+    }
+    // Stock is only taken when the seller accepts, this just rejects obviously impossible orders
+    if quantity > listing.amount {
+        return Err(format!("Only {} units are available.", listing.amount));
+    }
+
+    let order = Order::new(&listing, caller, quantity)?;
+    order::ORDERS.with(|orders| orders.borrow_mut().push(order.clone()));
+
+    notify(&order.seller_id, NotificationKind::OrderUpdated {
+        order_id: order.id,
+        listing_id,
+        status: order.status.clone(),
+    });
+
+    Ok(order)
+}
+
+#[ic_cdk::update]
+fn accept_order(order_id: u64) -> Result<Order, String> {
+    update_order_status(order_id, OrderStatus::Accepted)
+}
+
+#[ic_cdk::update]
+async fn reject_order(order_id: u64) -> Result<Order, String> {
+    let order = update_order_status(order_id, OrderStatus::Rejected)?;
+    settle_order_payment(&order).await;
+    Ok(order)
+}
+
+#[ic_cdk::update]
+fn ship_order(order_id: u64) -> Result<Order, String> {
+    update_order_status(order_id, OrderStatus::Shipped)
+}
+
+#[ic_cdk::update]
+async fn complete_order(order_id: u64) -> Result<Order, String> {
+    let order = update_order_status(order_id, OrderStatus::Completed)?;
+    settle_order_payment(&order).await;
+    Ok(order)
+}
+
+#[ic_cdk::update]
+async fn cancel_order(order_id: u64) -> Result<Order, String> {
+    let order = update_order_status(order_id, OrderStatus::Cancelled)?;
+    settle_order_payment(&order).await;
+    Ok(order)
+}
+
+#[ic_cdk::query]
+fn get_order(order_id: u64) -> Result<Order, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    match order::get_order(order_id) {
+        Some(order) if order.is_party(&caller) => Ok(order),
+        _ => Err("Order not found.".to_string()),
+    }
+}
+
+#[ic_cdk::query]
+fn get_buyer_orders() -> Vec<Order> {
+    let caller = ic_cdk::caller().to_string();
+    order::get_orders_by(|order| order.buyer_id == caller)
+}
+
+#[ic_cdk::query]
+fn get_seller_orders() -> Vec<Order> {
+    let caller = ic_cdk::caller().to_string();
+    order::get_orders_by(|order| order.seller_id == caller)
+}
+
+// Payment API endpoints
+#[ic_cdk::query]
+fn get_order_payment_instructions(order_id: u64) -> Result<PaymentInstructions, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    let order = match order::get_order(order_id) {
+        Some(order) if order.buyer_id == caller => order,
+        _ => return Err("Order not found.".to_string()),
+    };
+
+    Ok(PaymentInstructions {
+        ledger_id: escrow::ledger_id()?,
+        account: escrow::escrow_account(order_id),
+        amount: order_amount_due(&order)?,
+    })
+}
+
+/// Called by the buyer after transferring the order total to the payment account.
+#[ic_cdk::update]
+async fn confirm_order_payment(order_id: u64) -> Result<Escrow, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    let order = match order::get_order(order_id) {
+        Some(order) if order.buyer_id == caller => order,
+        _ => return Err("Order not found.".to_string()),
+    };
+    if !matches!(order.status, OrderStatus::Requested | OrderStatus::Accepted) {
+        return Err(format!("Cannot pay for an order in {:?} state.", order.status));
+    }
+
+    let amount = order_amount_due(&order)?;
+    let escrow = escrow::fund(order_id, order.buyer_id, order.seller_id.clone(), amount, ic_cdk::api::time()).await?;
+
+    // The order may have been cancelled, rejected or completed while the ledger was queried.
+    // Its status change found nothing to settle then, so the payment is settled here.
+    let order = order::get_order(order_id).ok_or("Order not found.".to_string())?;
+    settle_order_payment(&order).await;
+    if matches!(order.status, OrderStatus::Cancelled | OrderStatus::Rejected) {
+        return Err(format!("Order was {:?} before the payment was confirmed, the payment is refunded.", order.status));
+    }
+    let escrow = escrow::get_escrow(order_id).unwrap_or(escrow);
+
+    notify(&order.seller_id, NotificationKind::OrderUpdated {
+        order_id,
+        listing_id: order.listing_id,
+        status: order.status,
+    });
+
+    Ok(escrow)
+}
+
+#[ic_cdk::query]
+fn get_order_escrow(order_id: u64) -> Result<Escrow, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    match (order::get_order(order_id), escrow::get_escrow(order_id)) {
+        (Some(order), Some(escrow)) if order.is_party(&caller) => Ok(escrow),
+        (Some(order), None) if order.is_party(&caller) => Err("Order has not been paid.".to_string()),
+        _ => Err("Order not found.".to_string()),
+    }
+}
+
+/// Retries paying out the escrow of a finished order if the automatic payout failed.
+/// For cancelled and rejected orders this also refunds payments that were never confirmed,
+/// for completed ones it returns anything the buyer paid beyond the order total.
+#[ic_cdk::update]
+async fn settle_order_escrow(order_id: u64) -> Result<Escrow, String> {
+    let caller = ic_cdk::caller().to_string();
+    let now = ic_cdk::api::time();
+
+    match order::get_order(order_id) {
+        Some(order) if order.is_party(&caller) => match order.status {
+            OrderStatus::Completed => match escrow::get_escrow(order_id) {
+                Some(escrow) if escrow.status == escrow::EscrowStatus::Released => {
+                    escrow::refund_excess(order_id).await?;
+                    Ok(escrow)
+                }
+                _ => escrow::release(order_id, now).await,
+            },
+            OrderStatus::Cancelled | OrderStatus::Rejected => {
+                escrow::refund_balance(order_id, order.buyer_id, order.seller_id, now).await
+            }
+            _ => Err("Order is not finished yet.".to_string()),
+        },
+        _ => Err("Order not found.".to_string()),
+    }
+}
+
+#[ic_cdk::update]
+fn set_payment_ledger(ledger_id: candid::Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Permission denied: only controllers can configure payments.".to_string());
+    }
+
+    escrow::LEDGER_ID.with(|id| *id.borrow_mut() = Some(ledger_id));
+    Ok(())
+}
+
+fn order_amount_due(order: &Order) -> Result<u64, String> {
+    let payment_token = CONFIG.with(|config| config.borrow().payment_token.clone());
+
+    if order.total_price.currency != payment_token {
+        return Err(format!("Only orders priced in {} can be paid on-chain.", payment_token));
+    }
+    Ok(order.total_price.amount)
+}
+
+// Releases the escrow to the seller on completion, refunds the buyer on cancellation or rejection.
+// Failures stay recorded on the escrow and can be retried with settle_order_escrow.
+async fn settle_order_payment(order: &Order) {
+    let funded = escrow::get_escrow(order.id).is_some_and(|escrow| escrow.status == escrow::EscrowStatus::Funded);
+    if !funded {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let result = match order.status {
+        OrderStatus::Completed => escrow::release(order.id, now).await,
+        OrderStatus::Cancelled | OrderStatus::Rejected => escrow::refund(order.id, now).await,
+        _ => return,
+    };
+
+    if let Err(e) = result {
+        ic_cdk::print(format!("Failed to settle escrow of order {}: {}", order.id, e));
+    }
+}
+
+// Periodic job for paid orders that stalled: refunds the buyer if the seller never shipped,
+// pays the seller if the buyer never confirmed delivery
+async fn settle_timed_out_escrows() {
+    let now = ic_cdk::api::time();
+    let (escrow_timeout, delivery_timeout) = CONFIG.with(|config| {
+        let config = config.borrow();
+        (config.escrow_timeout_ns(), config.delivery_timeout_ns())
+    });
+
+    for escrow in escrow::funded_escrows() {
+        let closed = order::ORDERS.with(|orders| {
+            let mut orders = orders.borrow_mut();
+            let order = orders.iter_mut().find(|order| order.id == escrow.order_id)?;
+            let previous = order.status.clone();
+            let next = order.timed_out_status(escrow.funded_at, now, escrow_timeout, delivery_timeout)?;
+            order.close_by_system(next, now);
+            Some((order.clone(), previous))
+        });
+
+        let (order, previous) = match closed {
+            Some(closed) => closed,
+            None => continue,
+        };
+
+        LISTINGS.with(|listings| {
+            if let Some(listing) = listings.borrow_mut().iter_mut().find(|listing| listing.id == order.listing_id) {
+                // Only releases stock, which cannot fail
+                let _ = order::move_stock(listing, order.quantity, &previous, &order.status);
+            }
+        });
+
+        for user_id in [&order.buyer_id, &order.seller_id] {
+            notify(user_id, NotificationKind::OrderUpdated {
+                order_id: order.id,
+                listing_id: order.listing_id,
+                status: order.status.clone(),
+            });
+        }
+
+        settle_order_payment(&order).await;
+    }
+}
+
+fn update_order_status(order_id: u64, next: OrderStatus) -> Result<Order, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    let mut order = match order::get_order(order_id) {
+        Some(order) if order.is_party(&caller) => order,
+        _ => return Err("Order not found.".to_string()),
+    };
+    let previous = order.status.clone();
+
+    order.transition_to(next.clone(), &caller)?;
+
+    // Stock moves within this single update call, so two orders can never oversell a listing
+    LISTINGS.with(|listings| {
+        match listings.borrow_mut().iter_mut().find(|listing| listing.id == order.listing_id) {
+            Some(listing) => order::move_stock(listing, order.quantity, &previous, &next),
+            None if next == OrderStatus::Accepted => Err("Listing not found.".to_string()),
+            None => Ok(()),
+        }
+    })?;
+
+    order::ORDERS.with(|orders| {
+        if let Some(stored) = orders.borrow_mut().iter_mut().find(|stored| stored.id == order_id) {
+            *stored = order.clone();
+        }
+    });
+
+    let counterpart = if order.buyer_id == caller { &order.seller_id } else { &order.buyer_id };
+    notify(counterpart, NotificationKind::OrderUpdated {
+        order_id,
+        listing_id: order.listing_id,
+        status: order.status.clone(),
+    });
+
+    Ok(order)
+}
+
+// Offers
+#[ic_cdk::update]
+fn make_offer(conversation_id: String, quantity: u32, unit_price: f64, expires_in_hours: Option<u32>) -> Result<Offer, String> {
+    let caller = ic_cdk::caller().to_string();
+    ensure_not_banned(&caller)?;
+
+    create_offer(&conversation_id, &caller, quantity, unit_price, expires_in_hours)
+}
+
+#[ic_cdk::update]
+fn respond_to_offer(offer_id: u64, response: OfferResponse) -> Result<Offer, String> {
+    let caller = ic_cdk::caller().to_string();
+    ensure_not_banned(&caller)?;
+
+    let mut offer = match offer::get_offer(offer_id) {
+        Some(offer) if offer.from_id == caller || offer.to_id == caller => offer,
+        _ => return Err("Offer not found.".to_string()),
+    };
+    if let Err(e) = offer.ensure_open(&caller) {
+        // Persist the expiry so it is not recomputed on every read
+        offer::save_offer(&offer);
+        return Err(e);
+    }
+
+    match response {
+        OfferResponse::Accept => {
+            offer.order_id = Some(create_order_from_offer(&offer)?.id);
+            offer.close(OfferStatus::Accepted, ic_cdk::api::time());
+        }
+        OfferResponse::Decline => offer.close(OfferStatus::Declined, ic_cdk::api::time()),
+        OfferResponse::Counter { quantity, unit_price, expires_in_hours } => {
+            let counter = create_offer(&offer.conversation_id, &caller, quantity, unit_price, expires_in_hours)?;
+            offer.counter_offer_id = Some(counter.id);
+            offer.close(OfferStatus::Countered, ic_cdk::api::time());
         }
+    }
+
+    offer::save_offer(&offer);
+    handlers::broadcast_offer_to_conversation(&offer);
+    notify(&offer.from_id, NotificationKind::OfferUpdated {
+        offer_id: offer.id,
+        conversation_id: offer.conversation_id.clone(),
+        status: offer.status.clone(),
+    });
+
+    Ok(offer)
+}
+
+#[ic_cdk::query]
+fn get_conversation_offers(conversation_id: String) -> Result<Vec<Offer>, String> {
+    let caller = ic_cdk::caller().to_string();
+    if !chat::is_participant(&conversation_id, &caller) {
+        return Err("Not a participant in this conversation".to_string());
+    }
+
+    Ok(offer::get_conversation_offers(&conversation_id))
+}
+
+// Offers are made in the listing currency, for the listing the conversation is about
+fn create_offer(conversation_id: &str, from_id: &str, quantity: u32, unit_price: f64, expires_in_hours: Option<u32>) -> Result<Offer, String> {
+    let conversation = chat::CONVERSATIONS.with(|convs| convs.borrow().get(conversation_id).cloned());
+    let conversation = match conversation {
+        Some(conversation) if conversation.participants.iter().any(|p| p == from_id) => conversation,
+        Some(_) => return Err("Not a participant in this conversation".to_string()),
+        None => return Err("Conversation not found".to_string()),
+    };
+    let to_id = match conversation.participants.iter().find(|p| *p != from_id) {
+        Some(to_id) => to_id.clone(),
+        None => return Err("Conversation has no other participant".to_string()),
+    };
+
+    let listing = LISTINGS.with(|listings| {
+        listings.borrow().iter().find(|listing| listing.id == conversation.listing_id && listing.is_public()).cloned()
+    });
+    let listing = match listing {
+        Some(listing) => listing,
+        None => return Err("Listing is not available.".to_string()),
+    };
+
+    if quantity == 0 {
+        return Err("Quantity must be greater than 0.".to_string());
+    }
+    if quantity < listing.min_order_quantity {
+        return Err(format!("Minimum order quantity is {} units.", listing.min_order_quantity));
+    }
+    if quantity > listing.amount {
+        return Err(format!("Only {} units are available.", listing.amount));
+    }
 
-        Some("User not found!".to_string())
-    })
+    let config = CONFIG.with(|config| config.borrow().clone());
+    let unit_price = config.parse_price(unit_price, Some(listing.price.currency.clone()))?;
+    let decimals = config.find_currency(&unit_price.currency).map_or(0, |currency| currency.decimals);
+
+    let mut offer = Offer::new(
+        conversation_id.to_string(),
+        listing.id,
+        from_id.to_string(),
+        to_id,
+        quantity,
+        unit_price,
+        expires_in_hours,
+        ic_cdk::api::time(),
+    )?;
+
+    let summary = format!(
+        "Offer: {} units at {} {} per unit",
+        offer.quantity,
+        format_decimal(offer.unit_price.amount, decimals),
+        offer.unit_price.currency,
+    );
+    let message = chat::send_offer_message(conversation_id, from_id, offer.id, summary)?;
+    offer.message_id = message.id;
+    offer::insert_offer(&offer);
+
+    Ok(offer)
 }
 
+// An accepted offer is agreed by both sides, so the order starts accepted and takes the stock right away
+fn create_order_from_offer(offer: &Offer) -> Result<Order, String> {
+    let order = LISTINGS.with(|listings| {
+        let mut listings = listings.borrow_mut();
+        let listing = match listings.iter_mut().find(|listing| listing.id == offer.listing_id && listing.is_public()) {
+            Some(listing) => listing,
+            None => return Err("Listing is not available.".to_string()),
+        };
+
+        let buyer_id = if offer.from_id == listing.owner_id { offer.to_id.clone() } else { offer.from_id.clone() };
+        let mut order = Order::with_unit_price(listing, buyer_id, offer.quantity, offer.unit_price.clone())?;
+        let seller_id = listing.owner_id.clone();
+        order.transition_to(OrderStatus::Accepted, &seller_id)?;
+        order::reserve_stock(listing, offer.quantity)?;
+        Ok(order)
+    })?;
+
+    order::ORDERS.with(|orders| orders.borrow_mut().push(order.clone()));
+
+    for user_id in [&order.buyer_id, &order.seller_id] {
+        notify(user_id, NotificationKind::OrderUpdated {
+            order_id: order.id,
+            listing_id: order.listing_id,
+            status: order.status.clone(),
+        });
+    }
+
+    Ok(order)
+}
 
+// Saved searches and notifications
 #[ic_cdk::update]
-fn add_favorite_listing(listing_id: u64) {
-    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+fn save_search(
+    name: String,
+    categories_path: Option<String>,
+    keywords: Vec<String>,
+    price_range: Option<PriceRange>,
+) -> Result<SavedSearch, String> {
+    let caller = ic_cdk::caller().to_string();
 
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
+    if !USERS.with(|users| users.borrow().iter().any(|user| user.id == caller)) {
+        return Err("User not found!".to_string());
+    }
 
-        if let Some(user) = users.iter_mut().find(|user| user.id == caller) {
-            LISTINGS.with(|listings| {
-                if let Some(listing) = listings.borrow().iter().find(|listing| listing.id == listing_id) {
-                    if caller == listing.owner_id {
-                        return;
-                    }
-                    if user.favorites_id.is_none() {
-                        user.favorites_id = Some(vec![listing.id]);
-                    } else {
-                        user.favorites_id.as_mut().unwrap().push(listing.id);
-                    }
-                }
-            });
-        }
-    });
+    let search = SavedSearch::new(caller, name, categories_path, keywords, price_range);
+    saved_search::add_saved_search(search)
 }
 
 #[ic_cdk::query]
-fn get_active_user_favorite_listings() -> Vec<Listing> {
-    let user = get_active_user();
-    let mut favorites = Vec::new();
-    if let Some(user) = user {
-        if let Some(favorites_id) = user.favorites_id {
-            LISTINGS.with(|listings| {
-                for listing_id in favorites_id {
-                    if let Some(listing) = listings.borrow().iter().find(|listing| listing.id == listing_id) {
-                        favorites.push(listing.clone());
-                    }
-                }
-            });
-        }
-    }
-    favorites
+fn get_saved_searches() -> Vec<SavedSearch> {
+    let caller = ic_cdk::caller().to_string();
+    saved_search::get_user_saved_searches(&caller)
+}
+
+#[ic_cdk::update]
+fn delete_saved_search(id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller().to_string();
+    saved_search::remove_saved_search(id, &caller)
 }
 
 #[ic_cdk::query]
-fn get_listings_by_active_user() -> Result<Vec<Listing>, String> {
-    let caller = ic_cdk::caller().to_string();  // Zamieniamy Principal na String
+fn get_notifications(unread_only: bool, offset: u64, limit: u64) -> Vec<Notification> {
+    let caller = ic_cdk::caller().to_string();
+    notification::get_notifications(&caller, unread_only, offset, limit)
+}
 
-    let owner = USERS.with(|users| {
-        users.borrow().iter().find(|user| user.id == caller).cloned()
-    });
+#[ic_cdk::query]
+fn get_unread_notifications_count() -> u32 {
+    let caller = ic_cdk::caller().to_string();
+    notification::unread_count(&caller)
+}
 
-    // If user doesn't exist yet (e.g., during user creation), return empty array instead of error
-    if owner.is_none() {
-        return Ok(Vec::new());
-    }
+#[ic_cdk::update]
+fn mark_notification_read(notification_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller().to_string();
+    notification::mark_as_read(&caller, notification_id)
+}
 
-    let user_listings = LISTINGS.with(|listings| {
-        listings
-            .borrow()
-            .iter()
-            .filter(|listing| listing.owner_id == caller)
-            .cloned()
-            .collect()
-    });
-    Ok(user_listings)
+#[ic_cdk::update]
+fn mark_all_notifications_read() {
+    let caller = ic_cdk::caller().to_string();
+    notification::mark_all_as_read(&caller);
 }
 
-#[ic_cdk::query]
-fn get_reviews_of_listing(listing_id: u64) -> Result<Vec<Review>, String> {
-    LISTINGS.with(|listings| {
-        let listings = listings.borrow();
-        
-                if let Some(listing) = listings.iter().find(|listing| listing.id == listing_id) {
-            if let Some(reviews) = &listing.reviews {
-                Ok(reviews.clone())
-            } else {
-                Err("No reviews for this listing.".to_string())
-            }
-        } else {
-            Err("Listing not found.".to_string())
+// Moderation API endpoints
+#[ic_cdk::update]
+fn report_content(target: ReportTarget, reason: String) -> Result<Report, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    ensure_not_banned(&caller)?;
+
+    if let ReportTarget::ChatMessage { conversation_id, .. } = &target {
+        if !chat::is_participant(conversation_id, &caller) {
+            return Err("Not a participant in this conversation".to_string());
         }
-    })
+    }
+
+    match get_report_target_author(&target) {
+        Some(author_id) if author_id == caller => Err("You cannot report your own content.".to_string()),
+        Some(_) => moderation::add_report(caller, target, reason, ic_cdk::api::time()),
+        None => Err("Reported content not found.".to_string()),
+    }
 }
 
 #[ic_cdk::query]
-fn calculate_average_rating_of_listing(listing_id: u64) -> Result<f64, String> {
-    LISTINGS.with(|listings| {
-        let listings = listings.borrow();
+fn get_moderation_queue(status: Option<ReportStatus>, offset: u64, limit: u64) -> Result<Vec<Report>, String> {
+    let caller = ic_cdk::caller().to_string();
 
-        if let Some(listing) = listings.iter().find(|listing| listing.id == listing_id) {
-            if let Some(reviews) = &listing.reviews {
-                if reviews.is_empty() {
-                    return Err("No reviews for this listing.".to_string());
-                }
+    ensure_moderator(&caller)?;
 
-                let total_rating: u32 = reviews.iter().map(|review| review.rating as u32).sum();
-                let average_rating = total_rating as f64 / reviews.len() as f64;
+    Ok(moderation::get_reports(status, offset, limit))
+}
 
-                Ok(average_rating)
-            } else {
-                Err("No reviews for this listing.".to_string())
-            }
-        } else {
-            Err("Listing not found.".to_string())
-        }
-    })
+#[ic_cdk::query]
+fn get_moderation_log(offset: u64, limit: u64) -> Result<Vec<AuditEntry>, String> {
+    let caller = ic_cdk::caller().to_string();
+
+    ensure_moderator(&caller)?;
+
+    Ok(moderation::get_audit_log(offset, limit))
 }
 
 #[ic_cdk::update]
-fn add_review(listing_id: u64, rating: u8, comment: String) -> Result<Review, String> {
+fn moderate_report(report_id: u64, action: ModerationAction, note: String) -> Result<Report, String> {
     let caller = ic_cdk::caller().to_string();
 
-    // Walidacja oceny
-    if rating > 5 {
-        return Err("Rating must be between 0 and 5.".to_string());
-    }
+    ensure_moderator(&caller)?;
 
-    // Sprawdzenie, czy użytkownik istnieje
-    let user_exists = USERS.with(|users| users.borrow().iter().any(|user| user.id == caller));
-    if !user_exists {
-        return Err("User not found.".to_string());
-    }
+    let report = match moderation::get_report(report_id) {
+        Some(report) => report,
+        None => return Err("Report not found.".to_string()),
+    };
 
-    // Operacje na liście ogłoszeń
-    LISTINGS.with(|listings| {
-        let mut listings = listings.borrow_mut();
+    if report.status != ReportStatus::Open {
+        return Err("Report has already been handled.".to_string());
+    }
 
-        // Znajdź ogłoszenie
-        let listing = listings.iter_mut().find(|listing| listing.id == listing_id);
-        if let Some(listing) = listing {
-            // Sprawdzenie, czy użytkownik nie jest właścicielem ogłoszenia
-            if listing.owner_id == caller {
-                return Err("You cannot review your own listing.".to_string());
-            }
+    // Resolve the author before a deletion makes the content unreachable
+    let author_id = get_report_target_author(&report.target);
 
-            // Sprawdzenie, czy użytkownik już dodał opinię
-            if let Some(reviews) = &listing.reviews {
-                if reviews.iter().any(|review| review.owner_id == caller) {
-                    return Err("You have already added a review for this listing.".to_string());
-                }
-            }
+    apply_moderation_action(&report.target, &action, &caller)?;
 
-            // Dodanie nowej opinii
-            let review = Review::new(caller, rating, comment);
-            if let Some(reviews) = &mut listing.reviews {
-                reviews.push(review.clone());
-            } else {
-                listing.reviews = Some(vec![review.clone()]);
-            }
-            return Ok(review); // Zwrócenie nowej opinii
+    if let Some(author_id) = author_id {
+        if action != ModerationAction::Dismiss {
+            notify(&author_id, NotificationKind::Moderation {
+                target: report.target.clone(),
+                action: action.clone(),
+            });
         }
-    Err("Listing not found.".to_string()) // If the listing does not exist
-    })
-}
+    }
+
+    moderation::close_reports_for_target(&report.target, &caller, action.clone());
+    moderation::log_action(&caller, Some(report_id), report.target.clone(), action, note, ic_cdk::api::time());
 
+    match moderation::get_report(report_id) {
+        Some(report) => Ok(report),
+        None => Err("Report not found.".to_string()),
+    }
+}
 
 #[ic_cdk::update]
-fn edit_review(listing_id: u64, rating: u8, comment: String) -> Option<String> {
+fn unban_user(user_id: String) -> Result<(), String> {
     let caller = ic_cdk::caller().to_string();
 
-    if rating > 5 {
-        return Some("Rating must be between 0 and 5.".to_string());
+    ensure_moderator(&caller)?;
+
+    moderation::BANNED_USERS.with(|banned| banned.borrow_mut().retain(|b| b != &user_id));
+    moderation::log_action(&caller, None, ReportTarget::User(user_id), ModerationAction::Dismiss, "Unbanned".to_string(), ic_cdk::api::time());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn add_moderator(principal: String) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Permission denied: only controllers can manage moderators.".to_string());
     }
 
-    let owner = USERS.with(|users| {
-        users.borrow().iter().find(|user| user.id == caller).cloned()
+    moderation::MODERATORS.with(|moderators| {
+        let mut moderators = moderators.borrow_mut();
+        if !moderators.contains(&principal) {
+            moderators.push(principal);
+        }
     });
+    Ok(())
+}
 
-    if owner.is_none() {
-        return Some("User not found!".to_string());
+#[ic_cdk::update]
+fn remove_moderator(principal: String) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Permission denied: only controllers can manage moderators.".to_string());
     }
 
-    LISTINGS.with(|listings| {
-        let mut listings = listings.borrow_mut();
-        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == listing_id) {
-            if let Some(ref mut reviews) = listing.reviews {
-                if let Some(review) = reviews.iter_mut().find(|review| review.owner_id == caller) {
-                    review.rating = rating;
-                    review.comment = comment;
-                    return None;
-                }
-            }
-            Some("Review not found.".to_string())
-        } else {
-            Some("Listing not found.".to_string())
+    moderation::MODERATORS.with(|moderators| moderators.borrow_mut().retain(|m| m != &principal));
+    Ok(())
+}
+
+fn get_report_target_author(target: &ReportTarget) -> Option<String> {
+    match target {
+        ReportTarget::Listing(id) => LISTINGS.with(|listings| {
+            listings.borrow().iter().find(|listing| listing.id == *id).map(|listing| listing.owner_id.clone())
+        }),
+        ReportTarget::Review { listing_id, owner_id } => LISTINGS.with(|listings| {
+            listings
+                .borrow()
+                .iter()
+                .find(|listing| listing.id == *listing_id)
+                .and_then(|listing| listing.reviews.as_ref())
+                .and_then(|reviews| reviews.iter().find(|review| &review.owner_id == owner_id))
+                .map(|review| review.owner_id.clone())
+        }),
+        ReportTarget::User(user_id) => USERS.with(|users| {
+            users.borrow().iter().find(|user| &user.id == user_id).map(|user| user.id.clone())
+        }),
+        ReportTarget::ChatMessage { conversation_id, message_id } => {
+            chat::find_message(conversation_id, message_id).map(|message| message.sender_id)
         }
-    })
+    }
 }
 
-#[ic_cdk::update]
-fn delete_review(listing_id: u64) -> Option<String> {
-    let caller = ic_cdk::caller().to_string();
-    LISTINGS.with(|listings| {
-        let mut listings = listings.borrow_mut();
-        if let Some(listing) = listings.iter_mut().find(|listing| listing.id == listing_id) {
-            if let Some(ref mut reviews) = listing.reviews {
-                if let Some(index) = reviews.iter().position(|review| review.owner_id == caller) {
-                    reviews.remove(index);
-                    return None;
+fn apply_moderation_action(target: &ReportTarget, action: &ModerationAction, moderator_id: &str) -> Result<(), String> {
+    match action {
+        ModerationAction::Dismiss => Ok(()),
+        ModerationAction::BanAuthor => match get_report_target_author(target) {
+            Some(author_id) => {
+                moderation::ban_user(&author_id);
+                Ok(())
+            }
+            None => Err("Reported content not found.".to_string()),
+        },
+        ModerationAction::Hide => match target {
+            ReportTarget::Listing(id) => LISTINGS.with(|listings| {
+                match listings.borrow_mut().iter_mut().find(|listing| listing.id == *id) {
+                    Some(listing) => {
+                        listing.hidden = true;
+                        Ok(())
+                    }
+                    None => Err("Listing not found.".to_string()),
+                }
+            }),
+            ReportTarget::Review { listing_id, owner_id } => LISTINGS.with(|listings| {
+                let mut listings = listings.borrow_mut();
+                let review = listings
+                    .iter_mut()
+                    .find(|listing| listing.id == *listing_id)
+                    .and_then(|listing| listing.reviews.as_mut())
+                    .and_then(|reviews| reviews.iter_mut().find(|review| &review.owner_id == owner_id));
+                match review {
+                    Some(review) => {
+                        review.hidden = true;
+                        Ok(())
+                    }
+                    None => Err("Review not found.".to_string()),
                 }
+            }),
+            ReportTarget::ChatMessage { conversation_id, message_id } => {
+                chat::hide_message(conversation_id, message_id)
             }
-            Some("Review not found.".to_string())
-        } else {
-            Some("Listing not found.".to_string())
-        }
-    })
+            ReportTarget::User(_) => Err("Users cannot be hidden, ban them instead.".to_string()),
+        },
+        ModerationAction::Delete => match target {
+            ReportTarget::Listing(id) => remove_listing(*id, moderator_id).map(|_| ()),
+            ReportTarget::Review { listing_id, owner_id } => remove_review(*listing_id, owner_id, moderator_id).map(|_| ()),
+            ReportTarget::ChatMessage { conversation_id, message_id } => {
+                chat::remove_message(conversation_id, message_id, moderator_id).map(|_| ())
+            }
+            ReportTarget::User(_) => Err("Users cannot be deleted, ban them instead.".to_string()),
+        },
+    }
 }
 
 // Zmień init, aby przekazać handlery:
@@ -676,10 +2087,133 @@ fn init() {
     let gateway_principal = "va7o5-qcirk-xq622-h57sh-4g5wa-rlmzv-cnviv-wisvx-43fyw-ozbpw-4ae".to_string(); // <-- zamień na właściwy principal gatewaya
     let params = WsInitParams::new(handlers, gateway_principal);
     ic_websocket_cdk::init(params);
+
+    ic_cdk_timers::set_timer_interval(LISTING_EXPIRY_CHECK_INTERVAL, expire_listings);
+    ic_cdk_timers::set_timer_interval(LISTING_PURGE_CHECK_INTERVAL, purge_deleted_listings);
+    ic_cdk_timers::set_timer_interval(ESCROW_TIMEOUT_CHECK_INTERVAL, || ic_cdk::spawn(settle_timed_out_escrows()));
+}
+
+/// Everything the canister keeps across upgrades. Id counters are saved as well, so ids of
+/// deleted entries are never handed out again.
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    listings: Vec<Listing>,
+    listings_count: u64,
+    users: Vec<User>,
+    images: Vec<String>,
+    attachments: HashMap<u64, String>, // keeps chat attachments in `images` private to their conversation
+    conversations: HashMap<String, Conversation>,
+    user_conversations: HashMap<String, Vec<String>>,
+    conversation_keys: HashMap<String, String>,
+    messages_count: u64,
+    conversations_count: u64,
+    offers: HashMap<u64, Offer>,
+    offers_count: u64,
+    orders: Vec<Order>,
+    orders_count: u64,
+    escrows: HashMap<u64, Escrow>,
+    ledger_id: Option<candid::Principal>,
+    notifications: HashMap<String, Vec<Notification>>,
+    notifications_count: u64,
+    saved_searches: Vec<SavedSearch>,
+    saved_searches_count: u64,
+    reports: Vec<Report>,
+    audit_log: Vec<AuditEntry>,
+    moderators: Vec<String>,
+    banned_users: Vec<String>,
+    revisions: HashMap<u64, RevisionHistory>,
+}
+
+/// Layout of stable memory, a new version is added whenever `StableState` changes incompatibly
+/// and older ones are migrated in `restore_state`.
+#[derive(CandidType, Deserialize)]
+enum VersionedState {
+    V1(StableState),
+}
+
+impl StableState {
+    fn save() -> Self {
+        let (messages_count, conversations_count) = chat::counters();
+
+        Self {
+            listings: LISTINGS.with(|listings| listings.borrow().clone()),
+            listings_count: listing::listings_count(),
+            users: USERS.with(|users| users.borrow().clone()),
+            images: IMAGES.with(|images| images.borrow().clone()),
+            attachments: attachment::ATTACHMENTS.with(|attachments| attachments.borrow().clone()),
+            conversations: chat::CONVERSATIONS.with(|conversations| conversations.borrow().clone()),
+            user_conversations: chat::USER_CONVERSATIONS.with(|user_conversations| user_conversations.borrow().clone()),
+            conversation_keys: chat::CONVERSATION_KEYS.with(|keys| keys.borrow().clone()),
+            messages_count,
+            conversations_count,
+            offers: offer::OFFERS.with(|offers| offers.borrow().clone()),
+            offers_count: offer::offers_count(),
+            orders: order::ORDERS.with(|orders| orders.borrow().clone()),
+            orders_count: order::orders_count(),
+            escrows: escrow::ESCROWS.with(|escrows| escrows.borrow().clone()),
+            ledger_id: escrow::LEDGER_ID.with(|id| *id.borrow()),
+            notifications: notification::INBOX.with(|inbox| inbox.borrow().clone()),
+            notifications_count: notification::notifications_count(),
+            saved_searches: saved_search::SAVED_SEARCHES.with(|searches| searches.borrow().clone()),
+            saved_searches_count: saved_search::saved_searches_count(),
+            reports: moderation::REPORTS.with(|reports| reports.borrow().clone()),
+            audit_log: moderation::AUDIT_LOG.with(|log| log.borrow().clone()),
+            moderators: moderation::MODERATORS.with(|moderators| moderators.borrow().clone()),
+            banned_users: moderation::BANNED_USERS.with(|banned| banned.borrow().clone()),
+            revisions: revision::REVISIONS.with(|revisions| revisions.borrow().clone()),
+        }
+    }
+
+    fn restore(self) {
+        LISTINGS.with(|listings| *listings.borrow_mut() = self.listings);
+        listing::restore_listings_count(self.listings_count);
+        USERS.with(|users| *users.borrow_mut() = self.users);
+        IMAGES.with(|images| *images.borrow_mut() = self.images);
+        attachment::ATTACHMENTS.with(|attachments| *attachments.borrow_mut() = self.attachments);
+        chat::CONVERSATIONS.with(|conversations| *conversations.borrow_mut() = self.conversations);
+        chat::USER_CONVERSATIONS.with(|user_conversations| *user_conversations.borrow_mut() = self.user_conversations);
+        chat::CONVERSATION_KEYS.with(|keys| *keys.borrow_mut() = self.conversation_keys);
+        chat::restore_counters(self.messages_count, self.conversations_count);
+        offer::OFFERS.with(|offers| *offers.borrow_mut() = self.offers);
+        offer::restore_offers_count(self.offers_count);
+        order::ORDERS.with(|orders| *orders.borrow_mut() = self.orders);
+        order::restore_orders_count(self.orders_count);
+        escrow::ESCROWS.with(|escrows| *escrows.borrow_mut() = self.escrows);
+        escrow::LEDGER_ID.with(|id| *id.borrow_mut() = self.ledger_id);
+        notification::INBOX.with(|inbox| *inbox.borrow_mut() = self.notifications);
+        notification::restore_notifications_count(self.notifications_count);
+        saved_search::SAVED_SEARCHES.with(|searches| *searches.borrow_mut() = self.saved_searches);
+        saved_search::restore_saved_searches_count(self.saved_searches_count);
+        moderation::REPORTS.with(|reports| *reports.borrow_mut() = self.reports);
+        moderation::AUDIT_LOG.with(|log| *log.borrow_mut() = self.audit_log);
+        moderation::MODERATORS.with(|moderators| *moderators.borrow_mut() = self.moderators);
+        moderation::BANNED_USERS.with(|banned| *banned.borrow_mut() = self.banned_users);
+        revision::REVISIONS.with(|revisions| *revisions.borrow_mut() = self.revisions);
+    }
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((VersionedState::V1(StableState::save()),))
+        .expect("Failed to save state to stable memory");
+}
+
+fn restore_state() {
+    // Versions before stable state was introduced kept everything on the heap, nothing to restore
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return;
+    }
+
+    // Trapping rolls the upgrade back instead of starting over with an empty canister
+    let (state,): (VersionedState,) = ic_cdk::storage::stable_restore().expect("Failed to restore state from stable memory");
+    match state {
+        VersionedState::V1(state) => state.restore(),
+    }
 }
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
+    restore_state();
     init();
 }
 
@@ -705,3 +2239,120 @@ fn ws_get_messages(args: CanisterWsGetMessagesArguments) -> CanisterWsGetMessage
 
 candid::export_service!();
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_state_round_trips() {
+        let conversation_id = chat::tests::insert_conversation();
+        IMAGES.with(|images| images.borrow_mut().push("image".to_string()));
+        attachment::ATTACHMENTS.with(|attachments| attachments.borrow_mut().insert(0, conversation_id.clone()));
+        listing::restore_listings_count(12);
+        chat::restore_counters(30, 4);
+        moderation::ban_user("mallory");
+
+        let bytes = candid::encode_one(VersionedState::V1(StableState::save())).unwrap();
+
+        // Simulate the fresh heap of the upgraded canister
+        chat::CONVERSATIONS.with(|conversations| conversations.borrow_mut().clear());
+        IMAGES.with(|images| images.borrow_mut().clear());
+        attachment::ATTACHMENTS.with(|attachments| attachments.borrow_mut().clear());
+        listing::restore_listings_count(0);
+        chat::restore_counters(0, 0);
+        moderation::BANNED_USERS.with(|banned| banned.borrow_mut().clear());
+
+        match candid::decode_one(&bytes).unwrap() {
+            VersionedState::V1(state) => state.restore(),
+        }
+
+        assert!(chat::CONVERSATIONS.with(|conversations| conversations.borrow().contains_key(&conversation_id)));
+        assert_eq!(IMAGES.with(|images| images.borrow().clone()), vec!["image".to_string()]);
+        // The image is a chat attachment and must stay private after the upgrade
+        assert_eq!(get_image_by_id(0), None);
+        assert_eq!(listing::listings_count(), 12);
+        assert_eq!(chat::counters(), (30, 4));
+        assert!(moderation::is_banned("mallory"));
+    }
+
+    #[test]
+    fn favorites_are_counted_once_per_user() {
+        LISTINGS.with(|listings| listings.borrow_mut().push(order::tests::listing(5)));
+        USERS.with(|users| {
+            let mut users = users.borrow_mut();
+            for id in ["buyer", "seller"] {
+                users.push(User::new(id.to_string(), id.to_string(), String::new(), String::new(), String::new()));
+            }
+        });
+        let favorites_count = || LISTINGS.with(|listings| listings.borrow()[0].favorites_count);
+
+        assert_eq!(set_favorite("buyer", 1, true), Ok(true));
+        assert_eq!(set_favorite("buyer", 1, true), Ok(false));
+        assert_eq!(favorites_count(), 1);
+        assert!(set_favorite("seller", 1, true).is_err());
+        assert_eq!(notification::unread_count("seller"), 1);
+
+        assert_eq!(set_favorite("buyer", 1, false), Ok(true));
+        assert_eq!(set_favorite("buyer", 1, false), Ok(false));
+        assert_eq!(favorites_count(), 0);
+        assert!(set_favorite("buyer", 2, true).is_err());
+    }
+
+    fn images_patch(add: &[&str], remove: &[u64], order: Option<Vec<u64>>) -> ImagesPatch {
+        ImagesPatch {
+            add: add.iter().map(|image| image.to_string()).collect(),
+            remove: remove.to_vec(),
+            order,
+        }
+    }
+
+    #[test]
+    fn images_patches_remove_reorder_and_add() {
+        let mut listing = order::tests::listing(5);
+        listing.images_id = store_images(&["front".to_string(), "side".to_string(), "back".to_string()]);
+
+        let removed = apply_images_patch(&mut listing, &images_patch(&["top"], &[1, 1], Some(vec![2, 0]))).unwrap();
+        assert_eq!(removed, vec![1]);
+        assert_eq!(listing.images_id, vec![2, 0, 3]);
+        assert!(images_match(&listing.images_id, &["back".to_string(), "front".to_string(), "top".to_string()]));
+
+        // Without an order the kept images keep their positions
+        let removed = apply_images_patch(&mut listing, &images_patch(&[], &[0], None)).unwrap();
+        assert_eq!(removed, vec![0]);
+        assert_eq!(listing.images_id, vec![2, 3]);
+    }
+
+    #[test]
+    fn images_patch_orders_must_list_every_kept_image_once() {
+        let current = [0, 1, 2];
+
+        let invalid = [
+            images_patch(&[], &[7], None),
+            images_patch(&[], &[], Some(vec![0, 1])),
+            images_patch(&[], &[], Some(vec![0, 1, 1, 2])),
+            images_patch(&[], &[2], Some(vec![0, 1, 2])),
+            images_patch(&[], &[], Some(vec![0, 1, 2, 5])),
+        ];
+        for patch in invalid {
+            assert!(patch_image_ids(&current, &patch).is_err(), "{:?}", patch);
+        }
+        assert_eq!(patch_image_ids(&current, &images_patch(&[], &[1], Some(vec![2, 0]))), Ok((vec![2, 0], vec![1])));
+    }
+
+    #[test]
+    fn moderators_can_hide_listings_and_ban_their_authors() {
+        LISTINGS.with(|listings| listings.borrow_mut().push(order::tests::listing(5)));
+        let target = ReportTarget::Listing(1);
+
+        apply_moderation_action(&target, &ModerationAction::Hide, "moderator").unwrap();
+        assert!(LISTINGS.with(|listings| listings.borrow()[0].hidden));
+
+        apply_moderation_action(&target, &ModerationAction::BanAuthor, "moderator").unwrap();
+        assert!(moderation::is_banned("seller"));
+
+        apply_moderation_action(&ReportTarget::Listing(2), &ModerationAction::Dismiss, "moderator").unwrap();
+        assert!(apply_moderation_action(&ReportTarget::Listing(2), &ModerationAction::BanAuthor, "moderator").is_err());
+        assert!(apply_moderation_action(&ReportTarget::User("seller".to_string()), &ModerationAction::Hide, "moderator").is_err());
+    }
+}
@@ -2,9 +2,91 @@ use candid::{CandidType, Deserialize};
 use ic_cdk::api::time;
 use std::sync::atomic::{AtomicU64, Ordering};
 use crate::review::Review;
+use crate::attribute::{AttributeFilter, ListingAttribute};
+use crate::price::{Price, PriceRange, PriceTier, WholesalePricing};
 
 static AMOUNT_OF_LISTINGS: AtomicU64 = AtomicU64::new(0);
 
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub enum ListingStatus {
+    Draft,
+    Active,
+    Paused,
+    SoldOut,
+    Expired,
+    Archived,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub enum ListingSort {
+    Newest,
+    PriceAscending,
+    PriceDescending,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct ListingQuery {
+    pub categories_path: Option<String>,
+    pub price_range: Option<PriceRange>,
+    pub attributes: Option<Vec<AttributeFilter>>, // all filters must match
+    pub sort: Option<ListingSort>,
+}
+
+/// Listing as sent by clients, prices are decimal values in `currency` (default currency if None).
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct NewListing {
+    pub title: String,
+    pub description: String,
+    pub category: String,
+    pub price: f64,
+    pub currency: Option<String>,
+    pub wholesale_pricing: Option<WholesalePricing>,
+    pub amount: u32,
+    pub images_strings: Vec<String>,
+    pub categories_path: String,
+    pub attributes: Vec<ListingAttribute>,
+    pub draft: bool,
+}
+
+/// Changes to the listing images, new images are appended after the kept ones.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct ImagesPatch {
+    pub add: Vec<String>,
+    pub remove: Vec<u64>,
+    pub order: Option<Vec<u64>>, // new order of the kept image ids
+}
+
+/// Partial listing update, fields left as None keep their current value.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct ListingPatch {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub category: Option<String>,
+    pub categories_path: Option<String>,
+    pub price: Option<f64>,
+    pub currency: Option<String>,
+    pub wholesale_pricing: Option<WholesalePricing>,
+    pub amount: Option<u32>,
+    pub images: Option<ImagesPatch>,
+    pub attributes: Option<Vec<ListingAttribute>>, // replaces all attributes
+}
+
+impl ListingStatus {
+    pub fn can_transition_to(&self, next: &ListingStatus) -> bool {
+        use ListingStatus::*;
+
+        matches!(
+            (self, next),
+            (Draft, Active) | (Draft, Archived)
+                | (Active, Paused) | (Active, SoldOut) | (Active, Archived)
+                | (Paused, Active) | (Paused, Archived)
+                | (SoldOut, Active) | (SoldOut, Archived)
+                | (Active, Expired) | (Paused, Expired) | (SoldOut, Expired)
+                | (Expired, Active) | (Expired, Archived)
+        )
+    }
+}
+
 #[derive(Clone, CandidType, Deserialize, Debug)]
 pub struct Listing {
     pub id: u64,
@@ -12,12 +94,29 @@ pub struct Listing {
     pub date: u64,
     pub description: String,
     pub category: String,
-    pub price: f64,
+    pub price: Price,
+    pub min_order_quantity: u32,
+    pub price_tiers: Vec<PriceTier>, // sorted by min_quantity
     pub amount: u32,
     pub owner_id: String,
     pub images_id: Vec<u64>,
     pub categories_path: String,
+    pub attributes: Vec<ListingAttribute>,
     pub reviews: Option<Vec<Review>>,
+    pub hidden: bool,
+    pub favorites_count: u32,
+    pub status: ListingStatus,
+    pub expires_at: u64,
+    pub deleted_at: Option<u64>, // soft-deleted, purged once the restore window ends
+}
+
+pub fn listings_count() -> u64 {
+    AMOUNT_OF_LISTINGS.load(Ordering::SeqCst)
+}
+
+/// Restores the id counter after an upgrade, so ids of purged listings are not reused.
+pub fn restore_listings_count(count: u64) {
+    AMOUNT_OF_LISTINGS.store(count, Ordering::SeqCst);
 }
 
 impl Listing {
@@ -25,7 +124,7 @@ impl Listing {
         title: String,
         description: String,
         category: String,
-        price: f64,
+        price: Price,
         amount: u32,
         owner_id: String,
         images_id: Vec<u64>,
@@ -38,11 +137,173 @@ impl Listing {
             description,
             category,
             price,
+            min_order_quantity: 1,
+            price_tiers: Vec::new(),
             amount,
             owner_id,
             images_id,
             categories_path,
+            attributes: Vec::new(),
             reviews: None,
+            hidden: false,
+            favorites_count: 0,
+            status: if amount == 0 { ListingStatus::SoldOut } else { ListingStatus::Active },
+            expires_at: 0,
+            deleted_at: None,
+        }
+    }
+
+    /// Visible in public listing queries.
+    pub fn is_public(&self) -> bool {
+        !self.hidden && self.deleted_at.is_none() && self.status == ListingStatus::Active
+    }
+
+    pub fn transition_to(&mut self, next: ListingStatus) -> Result<(), String> {
+        if !self.status.can_transition_to(&next) {
+            return Err(format!("Cannot change listing status from {:?} to {:?}.", self.status, next));
+        }
+
+        // Resuming a listing without stock goes straight to sold out
+        self.status = if next == ListingStatus::Active && self.amount == 0 {
+            ListingStatus::SoldOut
+        } else {
+            next
+        };
+        Ok(())
+    }
+
+    /// Validates and applies the minimum order quantity and volume discounts.
+    pub fn set_wholesale_pricing(&mut self, min_order_quantity: u32, price_tiers: Vec<PriceTier>) -> Result<(), String> {
+        if min_order_quantity == 0 {
+            return Err("Minimum order quantity must be at least 1.".to_string());
         }
+
+        let mut previous_quantity = min_order_quantity;
+        let mut previous_amount = self.price.amount;
+        for tier in &price_tiers {
+            if tier.min_quantity <= previous_quantity {
+                return Err("Price tier quantities must be increasing and above the minimum order quantity.".to_string());
+            }
+            if tier.unit_amount == 0 || tier.unit_amount >= previous_amount {
+                return Err("Price tier unit prices must be decreasing and below the base price.".to_string());
+            }
+            previous_quantity = tier.min_quantity;
+            previous_amount = tier.unit_amount;
+        }
+
+        self.min_order_quantity = min_order_quantity;
+        self.price_tiers = price_tiers;
+        Ok(())
+    }
+
+    /// Unit price of the highest tier the quantity reaches, or the base price.
+    pub fn unit_price_for(&self, quantity: u32) -> Price {
+        let amount = self
+            .price_tiers
+            .iter()
+            .rev()
+            .find(|tier| quantity >= tier.min_quantity)
+            .map_or(self.price.amount, |tier| tier.unit_amount);
+
+        Price {
+            amount,
+            currency: self.price.currency.clone(),
+        }
+    }
+
+    /// True for the category itself and all of its subcategories, e.g. "Electronics" for "Electronics/Laptops".
+    pub fn in_category(&self, path: &str) -> bool {
+        self.categories_path == path || self.categories_path.starts_with(&format!("{}/", path))
+    }
+
+    /// Moves the expiry date to `duration_ns` from now.
+    pub fn renew(&mut self, duration_ns: u64) {
+        self.expires_at = time() + duration_ns;
+    }
+
+    pub fn can_expire_at(&self, now: u64) -> bool {
+        self.expires_at <= now && self.deleted_at.is_none() && self.status.can_transition_to(&ListingStatus::Expired)
+    }
+
+    /// Updates the stock and flips between active and sold out when it reaches or leaves zero.
+    pub fn set_amount(&mut self, amount: u32) {
+        self.amount = amount;
+
+        if amount == 0 && self.status == ListingStatus::Active {
+            self.status = ListingStatus::SoldOut;
+        } else if amount > 0 && self.status == ListingStatus::SoldOut {
+            self.status = ListingStatus::Active;
+        }
+    }
+}
+
+impl ListingQuery {
+    pub fn matches(&self, listing: &Listing) -> bool {
+        self.categories_path.as_ref().is_none_or(|path| listing.in_category(path))
+            && self.price_range.as_ref().is_none_or(|range| range.contains(&listing.price))
+            && self.attributes.as_ref().is_none_or(|filters| filters.iter().all(|filter| filter.matches(&listing.attributes)))
     }
-}
\ No newline at end of file
+
+    /// Prices are grouped by currency, since amounts in different currencies cannot be compared.
+    pub fn sort(&self, listings: &mut [Listing]) {
+        match self.sort.as_ref().unwrap_or(&ListingSort::Newest) {
+            ListingSort::Newest => listings.sort_by_key(|listing| std::cmp::Reverse(listing.date)),
+            ListingSort::PriceAscending => listings.sort_by(|a, b| {
+                a.price.compare(&b.price).unwrap_or_else(|| a.price.currency.cmp(&b.price.currency))
+            }),
+            ListingSort::PriceDescending => listings.sort_by(|a, b| {
+                b.price.compare(&a.price).unwrap_or_else(|| a.price.currency.cmp(&b.price.currency))
+            }),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::order::tests::listing;
+    use crate::price::PriceTier;
+
+    fn tier(min_quantity: u32, unit_amount: u64) -> PriceTier {
+        PriceTier { min_quantity, unit_amount }
+    }
+
+    #[test]
+    fn tiers_apply_from_their_minimum_quantity() {
+        let mut listing = listing(1_000);
+        listing.set_wholesale_pricing(5, vec![tier(10, 900), tier(50, 800)]).unwrap();
+
+        assert_eq!(listing.min_order_quantity, 5);
+        for (quantity, amount) in [(5, 1_000), (9, 1_000), (10, 900), (49, 900), (50, 800), (1_000, 800)] {
+            assert_eq!(listing.unit_price_for(quantity).amount, amount, "quantity {}", quantity);
+        }
+        assert_eq!(listing.unit_price_for(10).currency, "USD");
+    }
+
+    #[test]
+    fn tier_quantities_must_increase_above_the_minimum_order() {
+        let mut listing = listing(1_000);
+        assert!(listing.set_wholesale_pricing(10, vec![tier(10, 900)]).is_err());
+        assert!(listing.set_wholesale_pricing(5, vec![tier(20, 900), tier(20, 800)]).is_err());
+        assert!(listing.set_wholesale_pricing(5, vec![tier(20, 900), tier(10, 800)]).is_err());
+    }
+
+    #[test]
+    fn tier_prices_must_decrease_below_the_base_price() {
+        let mut listing = listing(1_000);
+        assert!(listing.set_wholesale_pricing(1, vec![tier(10, 1_000)]).is_err());
+        assert!(listing.set_wholesale_pricing(1, vec![tier(10, 900), tier(20, 900)]).is_err());
+        assert!(listing.set_wholesale_pricing(1, vec![tier(10, 0)]).is_err());
+    }
+
+    #[test]
+    fn rejected_pricing_leaves_the_listing_unchanged() {
+        let mut listing = listing(1_000);
+        listing.set_wholesale_pricing(2, vec![tier(10, 900)]).unwrap();
+
+        assert!(listing.set_wholesale_pricing(0, Vec::new()).is_err());
+        assert!(listing.set_wholesale_pricing(3, vec![tier(10, 950), tier(20, 990)]).is_err());
+        assert_eq!(listing.min_order_quantity, 2);
+        assert_eq!(listing.price_tiers, vec![tier(10, 900)]);
+    }
+}
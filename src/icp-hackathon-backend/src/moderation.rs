@@ -0,0 +1,266 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use serde::Serialize;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ReportTarget {
+    Listing(u64),
+    Review { listing_id: u64, owner_id: String }, // one review per user per listing
+    User(String),
+    ChatMessage { conversation_id: String, message_id: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ReportStatus {
+    Open,
+    Resolved,
+    Dismissed,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ModerationAction {
+    Hide,
+    Delete,
+    Dismiss,
+    BanAuthor,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Report {
+    pub id: u64,
+    pub reporter_id: String,
+    pub target: ReportTarget,
+    pub reason: String,
+    pub created_at: u64,
+    pub status: ReportStatus,
+    pub resolved_by: Option<String>,
+    pub resolution: Option<ModerationAction>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub moderator_id: String,
+    pub report_id: Option<u64>,
+    pub target: ReportTarget,
+    pub action: ModerationAction,
+    pub note: String,
+    pub timestamp: u64,
+}
+
+pub const MAX_REASON_LEN: usize = 500;
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+thread_local! {
+    pub static REPORTS: RefCell<Vec<Report>> = const { RefCell::new(Vec::new()) };
+    pub static AUDIT_LOG: RefCell<Vec<AuditEntry>> = const { RefCell::new(Vec::new()) };
+    pub static MODERATORS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    pub static BANNED_USERS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Report {
+    pub fn new(reporter_id: String, target: ReportTarget, reason: String, now: u64) -> Self {
+        let id = REPORTS.with(|reports| reports.borrow().len() as u64);
+
+        Self {
+            id,
+            reporter_id,
+            target,
+            reason,
+            created_at: now,
+            status: ReportStatus::Open,
+            resolved_by: None,
+            resolution: None,
+        }
+    }
+}
+
+pub fn is_moderator(user_id: &str) -> bool {
+    MODERATORS.with(|moderators| moderators.borrow().iter().any(|m| m == user_id))
+}
+
+pub fn ensure_moderator(user_id: &str) -> Result<(), String> {
+    if is_moderator(user_id) {
+        Ok(())
+    } else {
+        Err("Permission denied: moderator role required.".to_string())
+    }
+}
+
+pub fn is_banned(user_id: &str) -> bool {
+    BANNED_USERS.with(|banned| banned.borrow().iter().any(|b| b == user_id))
+}
+
+pub fn ensure_not_banned(user_id: &str) -> Result<(), String> {
+    if is_banned(user_id) {
+        Err("Your account has been banned.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn ban_user(user_id: &str) {
+    BANNED_USERS.with(|banned| {
+        let mut banned = banned.borrow_mut();
+        if !banned.iter().any(|b| b == user_id) {
+            banned.push(user_id.to_string());
+        }
+    });
+}
+
+pub fn add_report(reporter_id: String, target: ReportTarget, reason: String, now: u64) -> Result<Report, String> {
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err("Report reason length is out of range!".to_string());
+    }
+
+    let already_reported = REPORTS.with(|reports| {
+        reports.borrow().iter().any(|report| {
+            report.reporter_id == reporter_id
+                && report.target == target
+                && report.status == ReportStatus::Open
+        })
+    });
+    if already_reported {
+        return Err("You have already reported this content.".to_string());
+    }
+
+    let report = Report::new(reporter_id, target, reason, now);
+    REPORTS.with(|reports| reports.borrow_mut().push(report.clone()));
+    Ok(report)
+}
+
+pub fn get_reports(status: Option<ReportStatus>, offset: u64, limit: u64) -> Vec<Report> {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+
+    REPORTS.with(|reports| {
+        reports
+            .borrow()
+            .iter()
+            .filter(|report| status.as_ref().is_none_or(|s| &report.status == s))
+            .skip(offset as usize)
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn get_report(report_id: u64) -> Option<Report> {
+    REPORTS.with(|reports| reports.borrow().get(report_id as usize).cloned())
+}
+
+/// Closes every open report about `target`, e.g. when its author removed the content themselves.
+pub fn close_reports_for_target(target: &ReportTarget, resolved_by: &str, action: ModerationAction) {
+    let status = if action == ModerationAction::Dismiss {
+        ReportStatus::Dismissed
+    } else {
+        ReportStatus::Resolved
+    };
+
+    REPORTS.with(|reports| {
+        for report in reports.borrow_mut().iter_mut() {
+            if &report.target == target && report.status == ReportStatus::Open {
+                report.status = status.clone();
+                report.resolved_by = Some(resolved_by.to_string());
+                report.resolution = Some(action.clone());
+            }
+        }
+    });
+}
+
+pub fn log_action(moderator_id: &str, report_id: Option<u64>, target: ReportTarget, action: ModerationAction, note: String, now: u64) {
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let id = log.len() as u64;
+        log.push(AuditEntry {
+            id,
+            moderator_id: moderator_id.to_string(),
+            report_id,
+            target,
+            action,
+            note,
+            timestamp: now,
+        });
+    });
+}
+
+pub fn get_audit_log(offset: u64, limit: u64) -> Vec<AuditEntry> {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing_report(reporter_id: &str) -> Result<Report, String> {
+        add_report(reporter_id.to_string(), ReportTarget::Listing(1), "Counterfeit goods".to_string(), 0)
+    }
+
+    #[test]
+    fn open_reports_are_not_duplicated() {
+        assert_eq!(listing_report("alice").unwrap().id, 0);
+        assert!(listing_report("alice").is_err());
+        assert_eq!(listing_report("bob").unwrap().id, 1);
+
+        // Once handled the same content can be reported again
+        close_reports_for_target(&ReportTarget::Listing(1), "moderator", ModerationAction::Dismiss);
+        assert_eq!(listing_report("alice").unwrap().id, 2);
+    }
+
+    #[test]
+    fn report_reasons_are_required_and_bounded() {
+        let target = ReportTarget::User("bob".to_string());
+
+        assert!(add_report("alice".to_string(), target.clone(), "  ".to_string(), 0).is_err());
+        assert!(add_report("alice".to_string(), target.clone(), "x".repeat(MAX_REASON_LEN + 1), 0).is_err());
+        assert!(add_report("alice".to_string(), target, "x".repeat(MAX_REASON_LEN), 0).is_ok());
+    }
+
+    #[test]
+    fn dismissing_and_hiding_close_every_open_report_of_the_target() {
+        listing_report("alice").unwrap();
+        listing_report("bob").unwrap();
+        add_report("alice".to_string(), ReportTarget::Listing(2), "Spam".to_string(), 0).unwrap();
+
+        close_reports_for_target(&ReportTarget::Listing(1), "moderator", ModerationAction::Hide);
+
+        let resolved = get_reports(Some(ReportStatus::Resolved), 0, 10);
+        assert_eq!(resolved.len(), 2);
+        assert!(resolved.iter().all(|report| report.resolution == Some(ModerationAction::Hide)
+            && report.resolved_by.as_deref() == Some("moderator")));
+        assert_eq!(get_reports(Some(ReportStatus::Open), 0, 10).len(), 1);
+
+        close_reports_for_target(&ReportTarget::Listing(2), "moderator", ModerationAction::Dismiss);
+        assert_eq!(get_report(2).unwrap().status, ReportStatus::Dismissed);
+    }
+
+    #[test]
+    fn banned_users_are_rejected_until_unbanned() {
+        assert!(ensure_not_banned("mallory").is_ok());
+
+        ban_user("mallory");
+        ban_user("mallory");
+        assert!(ensure_not_banned("mallory").is_err());
+        assert_eq!(BANNED_USERS.with(|banned| banned.borrow().len()), 1);
+        assert!(ensure_not_banned("alice").is_ok());
+    }
+
+    #[test]
+    fn audit_log_is_newest_first() {
+        log_action("moderator", Some(0), ReportTarget::Listing(1), ModerationAction::Hide, String::new(), 1);
+        log_action("moderator", None, ReportTarget::User("bob".to_string()), ModerationAction::BanAuthor, String::new(), 2);
+
+        let log = get_audit_log(0, 10);
+        assert_eq!(log.iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![1, 0]);
+        assert_eq!(get_audit_log(1, 10).len(), 1);
+    }
+}
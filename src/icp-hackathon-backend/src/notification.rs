@@ -0,0 +1,172 @@
+use candid::{CandidType, Deserialize};
+use crate::clock::time;
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use crate::handlers::send_to_user;
+use crate::protocol::ServerEvent;
+use crate::moderation::{ModerationAction, ReportTarget};
+use crate::offer::OfferStatus;
+use crate::order::OrderStatus;
+
+static AMOUNT_OF_NOTIFICATIONS: AtomicU64 = AtomicU64::new(0);
+
+pub const MAX_NOTIFICATIONS_PER_USER: usize = 200;
+pub const MAX_PAGE_SIZE: u64 = 50;
+pub const PREVIEW_LEN: usize = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub enum NotificationKind {
+    SavedSearchMatch { search_id: u64, search_name: String, listing_id: u64, listing_title: String },
+    NewMessage { conversation_id: String, message_id: String, sender_id: String, preview: String },
+    NewReview { listing_id: u64, reviewer_id: String, rating: u8 },
+    ListingFavorited { listing_id: u64, user_id: String },
+    Moderation { target: ReportTarget, action: ModerationAction },
+    ListingExpired { listing_id: u64, listing_title: String },
+    OrderUpdated { order_id: u64, listing_id: u64, status: OrderStatus },
+    OfferUpdated { offer_id: u64, conversation_id: String, status: OfferStatus },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Notification {
+    pub id: u64,
+    pub user_id: String,
+    pub kind: NotificationKind,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+thread_local! {
+    pub static INBOX: RefCell<HashMap<String, Vec<Notification>>> = RefCell::new(HashMap::new());
+}
+
+pub fn notifications_count() -> u64 {
+    AMOUNT_OF_NOTIFICATIONS.load(Ordering::SeqCst)
+}
+
+/// Restores the id counter after an upgrade.
+pub fn restore_notifications_count(count: u64) {
+    AMOUNT_OF_NOTIFICATIONS.store(count, Ordering::SeqCst);
+}
+
+impl Notification {
+    pub fn new(user_id: String, kind: NotificationKind) -> Self {
+        Self {
+            id: AMOUNT_OF_NOTIFICATIONS.fetch_add(1, Ordering::SeqCst),
+            user_id,
+            kind,
+            created_at: time(),
+            read: false,
+        }
+    }
+}
+
+pub fn preview(content: &str) -> String {
+    content.chars().take(PREVIEW_LEN).collect()
+}
+
+/// Stores the notification in the user's inbox and pushes it over WebSocket if they are connected.
+pub fn notify(user_id: &str, kind: NotificationKind) {
+    let notification = store(user_id, kind);
+    send_to_user(user_id, ServerEvent::Notification(notification));
+}
+
+/// Stores the notification without pushing it, for events the client already receives over WebSocket.
+pub fn store(user_id: &str, kind: NotificationKind) -> Notification {
+    let notification = Notification::new(user_id.to_string(), kind);
+
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+        let notifications = inbox.entry(user_id.to_string()).or_insert_with(Vec::new);
+        notifications.push(notification.clone());
+
+        // Drop the oldest entries once the inbox is full
+        if notifications.len() > MAX_NOTIFICATIONS_PER_USER {
+            let overflow = notifications.len() - MAX_NOTIFICATIONS_PER_USER;
+            notifications.drain(..overflow);
+        }
+    });
+
+    notification
+}
+
+/// Rewrites the preview of the NewMessage notifications sent for a message that was hidden, edited or deleted.
+pub fn update_message_preview(user_ids: &[String], conversation_id: &str, message_id: &str, new_preview: String) {
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+
+        for user_id in user_ids {
+            let notifications = match inbox.get_mut(user_id) {
+                Some(notifications) => notifications,
+                None => continue,
+            };
+            for notification in notifications.iter_mut() {
+                if let NotificationKind::NewMessage { conversation_id: ref c, message_id: ref m, ref mut preview, .. } = notification.kind {
+                    if c == conversation_id && m == message_id {
+                        *preview = new_preview.clone();
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Newest first.
+pub fn get_notifications(user_id: &str, unread_only: bool, offset: u64, limit: u64) -> Vec<Notification> {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+
+    INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .get(user_id)
+            .map(|notifications| {
+                notifications
+                    .iter()
+                    .rev()
+                    .filter(|n| !unread_only || !n.read)
+                    .skip(offset as usize)
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+pub fn unread_count(user_id: &str) -> u32 {
+    INBOX.with(|inbox| {
+        inbox
+            .borrow()
+            .get(user_id)
+            .map_or(0, |notifications| notifications.iter().filter(|n| !n.read).count() as u32)
+    })
+}
+
+pub fn mark_as_read(user_id: &str, notification_id: u64) -> Result<(), String> {
+    INBOX.with(|inbox| {
+        let mut inbox = inbox.borrow_mut();
+
+        let notification = inbox
+            .get_mut(user_id)
+            .and_then(|notifications| notifications.iter_mut().find(|n| n.id == notification_id));
+
+        match notification {
+            Some(notification) => {
+                notification.read = true;
+                Ok(())
+            }
+            None => Err("Notification not found".to_string()),
+        }
+    })
+}
+
+pub fn mark_all_as_read(user_id: &str) {
+    INBOX.with(|inbox| {
+        if let Some(notifications) = inbox.borrow_mut().get_mut(user_id) {
+            for notification in notifications.iter_mut() {
+                notification.read = true;
+            }
+        }
+    });
+}
@@ -0,0 +1,247 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use crate::price::Price;
+
+static AMOUNT_OF_OFFERS: AtomicU64 = AtomicU64::new(0);
+
+pub const DEFAULT_OFFER_HOURS: u32 = 48;
+pub const MAX_OFFER_HOURS: u32 = 14 * 24;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum OfferStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Countered,
+    Expired,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub enum OfferResponse {
+    Accept,
+    Decline,
+    Counter { quantity: u32, unit_price: f64, expires_in_hours: Option<u32> },
+}
+
+/// Formal price proposal made in a conversation, only `to_id` can respond to it.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Offer {
+    pub id: u64,
+    pub conversation_id: String,
+    pub message_id: String, // chat message the offer was posted as
+    pub listing_id: u64,
+    pub from_id: String,
+    pub to_id: String,
+    pub quantity: u32,
+    pub unit_price: Price,
+    pub total_price: Price,
+    pub status: OfferStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub responded_at: Option<u64>,
+    pub counter_offer_id: Option<u64>, // set when the offer was answered with a counter offer
+    pub order_id: Option<u64>,         // set when the offer was accepted
+}
+
+thread_local! {
+    pub static OFFERS: RefCell<HashMap<u64, Offer>> = RefCell::new(HashMap::new());
+}
+
+pub fn offers_count() -> u64 {
+    AMOUNT_OF_OFFERS.load(Ordering::SeqCst)
+}
+
+/// Restores the id counter after an upgrade.
+pub fn restore_offers_count(count: u64) {
+    AMOUNT_OF_OFFERS.store(count, Ordering::SeqCst);
+}
+
+impl Offer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        conversation_id: String,
+        listing_id: u64,
+        from_id: String,
+        to_id: String,
+        quantity: u32,
+        unit_price: Price,
+        expires_in_hours: Option<u32>,
+        now: u64,
+    ) -> Result<Self, String> {
+        let hours = expires_in_hours.unwrap_or(DEFAULT_OFFER_HOURS);
+        if hours == 0 || hours > MAX_OFFER_HOURS {
+            return Err(format!("Offers can be valid for 1 to {} hours.", MAX_OFFER_HOURS));
+        }
+        let total_price = unit_price.checked_mul(quantity).ok_or("Offer total is too large.".to_string())?;
+        let timestamp = now;
+
+        Ok(Self {
+            // Only claimed by `insert_offer`, so an offer that fails to be posted does not use up an id
+            id: AMOUNT_OF_OFFERS.load(Ordering::SeqCst),
+            conversation_id,
+            message_id: String::new(),
+            listing_id,
+            from_id,
+            to_id,
+            quantity,
+            unit_price,
+            total_price,
+            status: OfferStatus::Pending,
+            created_at: timestamp,
+            expires_at: timestamp + hours as u64 * 60 * 60 * 1_000_000_000,
+            responded_at: None,
+            counter_offer_id: None,
+            order_id: None,
+        })
+    }
+
+    /// Pending offers past their expiry are reported as expired.
+    pub fn refresh(&mut self, now: u64) {
+        if self.status == OfferStatus::Pending && self.expires_at <= now {
+            self.status = OfferStatus::Expired;
+        }
+    }
+
+    /// Checks that `user_id` may still respond to the offer.
+    pub fn ensure_open(&self, user_id: &str) -> Result<(), String> {
+        if self.to_id != user_id {
+            return Err("Permission denied: Only the recipient can respond to this offer.".to_string());
+        }
+        match self.status {
+            OfferStatus::Pending => Ok(()),
+            OfferStatus::Expired => Err("Offer has expired.".to_string()),
+            _ => Err(format!("Offer is already {:?}.", self.status)),
+        }
+    }
+
+    pub fn close(&mut self, status: OfferStatus, now: u64) {
+        self.status = status;
+        self.responded_at = Some(now);
+    }
+}
+
+pub fn get_offer(offer_id: u64) -> Option<Offer> {
+    OFFERS.with(|offers| {
+        offers.borrow().get(&offer_id).cloned().map(|mut offer| {
+            offer.refresh(time());
+            offer
+        })
+    })
+}
+
+/// Stores a new offer and claims its id.
+pub fn insert_offer(offer: &Offer) {
+    let id = AMOUNT_OF_OFFERS.fetch_add(1, Ordering::SeqCst);
+    debug_assert_eq!(id, offer.id);
+    save_offer(offer);
+}
+
+pub fn save_offer(offer: &Offer) {
+    OFFERS.with(|offers| {
+        offers.borrow_mut().insert(offer.id, offer.clone());
+    });
+}
+
+/// Oldest first.
+pub fn get_conversation_offers(conversation_id: &str) -> Vec<Offer> {
+    let now = time();
+    let mut offers: Vec<Offer> = OFFERS.with(|offers| {
+        offers
+            .borrow()
+            .values()
+            .filter(|offer| offer.conversation_id == conversation_id)
+            .cloned()
+            .collect()
+    });
+
+    offers.iter_mut().for_each(|offer| offer.refresh(now));
+    offers.sort_by_key(|offer| offer.id);
+    offers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn offer(from_id: &str, to_id: &str, expires_in_hours: Option<u32>) -> Offer {
+        let unit_price = Price { amount: 950, currency: "USD".to_string() };
+        Offer::new("conv-1".to_string(), 1, from_id.to_string(), to_id.to_string(), 10, unit_price, expires_in_hours, 0).unwrap()
+    }
+
+    #[test]
+    fn only_the_recipient_can_respond() {
+        let offer = offer("buyer", "seller", None);
+
+        assert_eq!(offer.total_price.amount, 9_500);
+        assert!(offer.ensure_open("seller").is_ok());
+        assert!(offer.ensure_open("buyer").is_err());
+        assert!(offer.ensure_open("someone-else").is_err());
+    }
+
+    #[test]
+    fn accepted_and_declined_offers_are_closed() {
+        for status in [OfferStatus::Accepted, OfferStatus::Declined] {
+            let mut offer = offer("buyer", "seller", None);
+            offer.close(status.clone(), HOUR);
+
+            assert_eq!(offer.status, status);
+            assert_eq!(offer.responded_at, Some(HOUR));
+            assert!(offer.ensure_open("seller").is_err());
+
+            // Closed offers never turn into expired ones
+            offer.refresh(u64::MAX);
+            assert_eq!(offer.status, status);
+        }
+    }
+
+    #[test]
+    fn counter_offer_goes_back_to_the_original_sender() {
+        let mut original = offer("buyer", "seller", None);
+        let counter = offer("seller", "buyer", Some(24));
+
+        original.counter_offer_id = Some(counter.id);
+        original.close(OfferStatus::Countered, HOUR);
+
+        assert!(original.ensure_open("seller").is_err());
+        assert!(counter.ensure_open("buyer").is_ok());
+        assert!(counter.ensure_open("seller").is_err());
+    }
+
+    #[test]
+    fn pending_offers_expire() {
+        let mut offer = offer("buyer", "seller", Some(2));
+
+        offer.refresh(2 * HOUR - 1);
+        assert_eq!(offer.status, OfferStatus::Pending);
+
+        offer.refresh(2 * HOUR);
+        assert_eq!(offer.status, OfferStatus::Expired);
+        assert_eq!(offer.ensure_open("seller"), Err("Offer has expired.".to_string()));
+    }
+
+    #[test]
+    fn validity_is_bounded() {
+        let unit_price = Price { amount: 1, currency: "USD".to_string() };
+        for hours in [0, MAX_OFFER_HOURS + 1] {
+            let result = Offer::new("conv-1".to_string(), 1, "a".to_string(), "b".to_string(), 1, unit_price.clone(), Some(hours), 0);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn ids_are_only_used_by_stored_offers() {
+        let discarded = offer("buyer", "seller", None);
+        let stored = offer("buyer", "seller", None);
+        assert_eq!(discarded.id, stored.id);
+
+        insert_offer(&stored);
+        assert_eq!(offer("buyer", "seller", None).id, stored.id + 1);
+        assert!(OFFERS.with(|offers| offers.borrow().contains_key(&stored.id)));
+    }
+}
@@ -0,0 +1,326 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use crate::listing::Listing;
+use crate::price::Price;
+
+static AMOUNT_OF_ORDERS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum OrderStatus {
+    Requested,
+    Accepted,
+    Rejected,
+    Shipped,
+    Completed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct OrderStatusChange {
+    pub status: OrderStatus,
+    pub changed_by: String,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Order {
+    pub id: u64,
+    pub listing_id: u64,
+    pub listing_title: String,
+    pub buyer_id: String,
+    pub seller_id: String,
+    pub quantity: u32,
+    pub unit_price: Price,
+    pub total_price: Price,
+    pub status: OrderStatus,
+    pub created_at: u64,
+    pub history: Vec<OrderStatusChange>,
+}
+
+thread_local! {
+    pub static ORDERS: RefCell<Vec<Order>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Order {
+    pub fn new(listing: &Listing, buyer_id: String, quantity: u32) -> Result<Self, String> {
+        Self::with_unit_price(listing, buyer_id, quantity, listing.unit_price_for(quantity))
+    }
+
+    /// Order at a negotiated price instead of the listing price, e.g. from an accepted offer.
+    pub fn with_unit_price(listing: &Listing, buyer_id: String, quantity: u32, unit_price: Price) -> Result<Self, String> {
+        let timestamp = time();
+        if quantity < listing.min_order_quantity {
+            return Err(format!("Minimum order quantity is {} units.", listing.min_order_quantity));
+        }
+
+        let total_price = unit_price.checked_mul(quantity).ok_or("Order total is too large.".to_string())?;
+
+        Ok(Self {
+            id: AMOUNT_OF_ORDERS.fetch_add(1, Ordering::SeqCst),
+            listing_id: listing.id,
+            listing_title: listing.title.clone(),
+            buyer_id: buyer_id.clone(),
+            seller_id: listing.owner_id.clone(),
+            quantity,
+            unit_price,
+            total_price,
+            status: OrderStatus::Requested,
+            created_at: timestamp,
+            history: vec![OrderStatusChange {
+                status: OrderStatus::Requested,
+                changed_by: buyer_id,
+                timestamp,
+            }],
+        })
+    }
+
+    pub fn is_party(&self, user_id: &str) -> bool {
+        self.buyer_id == user_id || self.seller_id == user_id
+    }
+
+    /// Checks that `actor` is allowed to move the order to `next` and records the change.
+    pub fn transition_to(&mut self, next: OrderStatus, actor: &str) -> Result<(), String> {
+        use OrderStatus::*;
+
+        let is_seller = self.seller_id == actor;
+        let is_buyer = self.buyer_id == actor;
+
+        let allowed = match (&self.status, &next) {
+            (Requested, Accepted) | (Requested, Rejected) | (Accepted, Shipped) => is_seller,
+            (Shipped, Completed) => is_buyer,
+            (Requested, Cancelled) | (Accepted, Cancelled) => is_seller || is_buyer,
+            _ => return Err(format!("Cannot change order status from {:?} to {:?}.", self.status, next)),
+        };
+        if !allowed {
+            return Err("Permission denied: You cannot perform this action on the order.".to_string());
+        }
+
+        self.status = next.clone();
+        self.history.push(OrderStatusChange {
+            status: next,
+            changed_by: actor.to_string(),
+            timestamp: time(),
+        });
+        Ok(())
+    }
+
+    /// When the order was shipped, the start of the buyer's window to confirm delivery.
+    pub fn shipped_at(&self) -> Option<u64> {
+        self.history.iter().rev().find(|change| change.status == OrderStatus::Shipped).map(|change| change.timestamp)
+    }
+
+    /// Where the canister moves a paid order once a party stopped responding: orders the seller never
+    /// shipped are cancelled `escrow_timeout_ns` after payment, shipped orders the buyer never confirmed
+    /// are completed `delivery_timeout_ns` after shipping.
+    pub fn timed_out_status(&self, funded_at: u64, now: u64, escrow_timeout_ns: u64, delivery_timeout_ns: u64) -> Option<OrderStatus> {
+        match self.status {
+            OrderStatus::Requested | OrderStatus::Accepted if funded_at.saturating_add(escrow_timeout_ns) <= now => {
+                Some(OrderStatus::Cancelled)
+            }
+            OrderStatus::Shipped => self
+                .shipped_at()
+                .filter(|shipped_at| shipped_at.saturating_add(delivery_timeout_ns) <= now)
+                .map(|_| OrderStatus::Completed),
+            _ => None,
+        }
+    }
+
+    /// Moves an order to `next` on behalf of the canister, e.g. when its escrow timed out.
+    pub fn close_by_system(&mut self, next: OrderStatus, now: u64) {
+        self.status = next.clone();
+        self.history.push(OrderStatusChange {
+            status: next,
+            changed_by: "system".to_string(),
+            timestamp: now,
+        });
+    }
+}
+
+pub fn orders_count() -> u64 {
+    AMOUNT_OF_ORDERS.load(Ordering::SeqCst)
+}
+
+/// Restores the id counter after an upgrade.
+pub fn restore_orders_count(count: u64) {
+    AMOUNT_OF_ORDERS.store(count, Ordering::SeqCst);
+}
+
+/// Takes `quantity` units out of the listing stock, refusing to go below zero.
+pub fn reserve_stock(listing: &mut Listing, quantity: u32) -> Result<(), String> {
+    // Paused, archived, hidden or deleted listings cannot take new orders
+    if !listing.is_public() {
+        return Err("Listing is not available.".to_string());
+    }
+    if quantity == 0 {
+        return Err("Quantity must be greater than 0.".to_string());
+    }
+    if quantity > listing.amount {
+        return Err(format!("Only {} units are available.", listing.amount));
+    }
+
+    listing.set_amount(listing.amount - quantity);
+    Ok(())
+}
+
+pub fn release_stock(listing: &mut Listing, quantity: u32) {
+    listing.set_amount(listing.amount.saturating_add(quantity));
+}
+
+/// Stock moves when the seller accepts, and comes back if an accepted order is cancelled.
+pub fn move_stock(listing: &mut Listing, quantity: u32, previous: &OrderStatus, next: &OrderStatus) -> Result<(), String> {
+    match (previous, next) {
+        (_, OrderStatus::Accepted) => reserve_stock(listing, quantity),
+        (OrderStatus::Accepted, OrderStatus::Cancelled) => {
+            release_stock(listing, quantity);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn get_order(order_id: u64) -> Option<Order> {
+    ORDERS.with(|orders| orders.borrow().iter().find(|order| order.id == order_id).cloned())
+}
+
+pub fn get_orders_by<F>(filter: F) -> Vec<Order>
+where
+    F: Fn(&Order) -> bool,
+{
+    ORDERS.with(|orders| {
+        orders.borrow().iter().filter(|&order| filter(order)).rev().cloned().collect()
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::listing::ListingStatus;
+
+    /// Active listing 1 of "seller" with `amount` units in stock.
+    pub(crate) fn listing(amount: u32) -> Listing {
+        Listing {
+            id: 1,
+            title: "Pallet of bricks".to_string(),
+            date: 0,
+            description: "Red clay bricks".to_string(),
+            category: "Construction".to_string(),
+            price: Price { amount: 1_000, currency: "USD".to_string() },
+            min_order_quantity: 1,
+            price_tiers: Vec::new(),
+            amount,
+            owner_id: "seller".to_string(),
+            images_id: Vec::new(),
+            categories_path: "Construction".to_string(),
+            attributes: Vec::new(),
+            reviews: None,
+            hidden: false,
+            favorites_count: 0,
+            status: ListingStatus::Active,
+            expires_at: u64::MAX,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn accepts_cannot_oversell() {
+        let mut listing = listing(10);
+
+        assert!(move_stock(&mut listing, 6, &OrderStatus::Requested, &OrderStatus::Accepted).is_ok());
+        assert!(move_stock(&mut listing, 6, &OrderStatus::Requested, &OrderStatus::Accepted).is_err());
+        assert_eq!(listing.amount, 4);
+
+        assert!(move_stock(&mut listing, 4, &OrderStatus::Requested, &OrderStatus::Accepted).is_ok());
+        assert_eq!(listing.amount, 0);
+        assert_eq!(listing.status, ListingStatus::SoldOut);
+    }
+
+    #[test]
+    fn cancelling_an_accepted_order_restores_stock() {
+        let mut listing = listing(5);
+
+        move_stock(&mut listing, 5, &OrderStatus::Requested, &OrderStatus::Accepted).unwrap();
+        assert_eq!(listing.status, ListingStatus::SoldOut);
+
+        move_stock(&mut listing, 5, &OrderStatus::Accepted, &OrderStatus::Cancelled).unwrap();
+        assert_eq!(listing.amount, 5);
+        assert_eq!(listing.status, ListingStatus::Active);
+    }
+
+    #[test]
+    fn rejecting_or_cancelling_a_request_leaves_stock_alone() {
+        let mut listing = listing(5);
+
+        move_stock(&mut listing, 3, &OrderStatus::Requested, &OrderStatus::Rejected).unwrap();
+        move_stock(&mut listing, 3, &OrderStatus::Requested, &OrderStatus::Cancelled).unwrap();
+        assert_eq!(listing.amount, 5);
+    }
+
+    #[test]
+    fn unavailable_listings_cannot_be_reserved() {
+        for status in [ListingStatus::Paused, ListingStatus::Archived, ListingStatus::Draft] {
+            let mut listing = listing(5);
+            listing.status = status;
+            assert!(reserve_stock(&mut listing, 1).is_err());
+            assert_eq!(listing.amount, 5);
+        }
+
+        let mut hidden = listing(5);
+        hidden.hidden = true;
+        assert!(reserve_stock(&mut hidden, 1).is_err());
+
+        let mut deleted = listing(5);
+        deleted.deleted_at = Some(1);
+        assert!(reserve_stock(&mut deleted, 1).is_err());
+    }
+
+    fn order(status: OrderStatus, history: Vec<(OrderStatus, u64)>) -> Order {
+        Order {
+            id: 1,
+            listing_id: 1,
+            listing_title: "Pallet of bricks".to_string(),
+            buyer_id: "buyer".to_string(),
+            seller_id: "seller".to_string(),
+            quantity: 1,
+            unit_price: Price { amount: 1_000, currency: "ICP".to_string() },
+            total_price: Price { amount: 1_000, currency: "ICP".to_string() },
+            status,
+            created_at: 0,
+            history: history
+                .into_iter()
+                .map(|(status, timestamp)| OrderStatusChange { status, changed_by: "seller".to_string(), timestamp })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn unshipped_paid_orders_are_cancelled_after_the_escrow_timeout() {
+        let accepted = order(OrderStatus::Accepted, vec![(OrderStatus::Accepted, 5)]);
+
+        assert_eq!(accepted.timed_out_status(10, 19, 10, 100), None);
+        assert_eq!(accepted.timed_out_status(10, 20, 10, 100), Some(OrderStatus::Cancelled));
+    }
+
+    #[test]
+    fn shipped_orders_are_completed_after_the_delivery_timeout() {
+        let shipped = order(OrderStatus::Shipped, vec![(OrderStatus::Accepted, 5), (OrderStatus::Shipped, 50)]);
+
+        // The delivery window runs from shipping, not from payment
+        assert_eq!(shipped.timed_out_status(10, 149, 10, 100), None);
+        assert_eq!(shipped.timed_out_status(10, 150, 10, 100), Some(OrderStatus::Completed));
+
+        let mut closed = shipped.clone();
+        closed.close_by_system(OrderStatus::Completed, 150);
+        assert_eq!(closed.status, OrderStatus::Completed);
+        assert_eq!(closed.history.last().unwrap().changed_by, "system");
+    }
+
+    #[test]
+    fn finished_orders_do_not_time_out() {
+        for status in [OrderStatus::Completed, OrderStatus::Cancelled, OrderStatus::Rejected] {
+            assert_eq!(order(status, Vec::new()).timed_out_status(0, u64::MAX, 0, 0), None);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cmp::Ordering;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct Currency {
+    pub code: String, // ISO 4217 code or token symbol
+    pub decimals: u8,
+}
+
+/// Fixed-point price, `amount` is expressed in the smallest unit of `currency` (cents, e8s...).
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Price {
+    pub amount: u64,
+    pub currency: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PriceRange {
+    pub currency: String,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+/// Unit price applied to orders of at least `min_quantity` units, in the listing currency.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct PriceTier {
+    pub min_quantity: u32,
+    pub unit_amount: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PriceTierInput {
+    pub min_quantity: u32,
+    pub unit_price: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct WholesalePricing {
+    pub min_order_quantity: u32,
+    pub price_tiers: Vec<PriceTierInput>,
+}
+
+impl Currency {
+    pub fn new(code: &str, decimals: u8) -> Self {
+        Self {
+            code: code.to_string(),
+            decimals,
+        }
+    }
+}
+
+impl Price {
+    /// Converts a decimal price, rejecting values with more precision than the currency allows.
+    pub fn from_f64(value: f64, currency: &Currency) -> Result<Self, String> {
+        if !value.is_finite() || value <= 0.0 {
+            return Err("Price must be greater than 0.".to_string());
+        }
+
+        let scaled = value * 10f64.powi(currency.decimals as i32);
+        let amount = scaled.round();
+        // Large amounts cannot be represented exactly, allow for a few ulps of error
+        if (scaled - amount).abs() > f64::max(1e-6, scaled * f64::EPSILON * 4.0) {
+            return Err(format!("{} prices can have at most {} decimal places.", currency.code, currency.decimals));
+        }
+        if amount >= u64::MAX as f64 {
+            return Err("Price is too large.".to_string());
+        }
+
+        Ok(Self {
+            amount: amount as u64,
+            currency: currency.code.clone(),
+        })
+    }
+
+    pub fn checked_mul(&self, quantity: u32) -> Option<Self> {
+        Some(Self {
+            amount: self.amount.checked_mul(quantity as u64)?,
+            currency: self.currency.clone(),
+        })
+    }
+
+    /// Prices in different currencies are not comparable.
+    pub fn compare(&self, other: &Price) -> Option<Ordering> {
+        if self.currency == other.currency {
+            Some(self.amount.cmp(&other.amount))
+        } else {
+            None
+        }
+    }
+}
+
+impl PriceRange {
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err("Minimum price cannot be greater than maximum price.".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, price: &Price) -> bool {
+        price.currency == self.currency
+            && self.min.is_none_or(|min| price.amount >= min)
+            && self.max.is_none_or(|max| price.amount <= max)
+    }
+}
+
+/// Decimal value of a fixed-point amount, the inverse of `Price::from_f64`.
+pub fn to_decimal(amount: u64, decimals: u8) -> f64 {
+    amount as f64 / 10f64.powi(decimals as i32)
+}
+
+/// Decimal representation of a fixed-point amount, e.g. 1250 with 2 decimals as "12.50".
+pub fn format_decimal(amount: u64, decimals: u8) -> String {
+    if decimals == 0 {
+        return amount.to_string();
+    }
+
+    let divisor = 10u64.pow(decimals as u32);
+    format!("{}.{:0width$}", amount / divisor, amount % divisor, width = decimals as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd() -> Currency {
+        Currency::new("USD", 2)
+    }
+
+    #[test]
+    fn decimal_prices_are_converted_to_the_smallest_unit() {
+        assert_eq!(Price::from_f64(12.5, &usd()).unwrap().amount, 1_250);
+        assert_eq!(Price::from_f64(19.99, &usd()).unwrap().amount, 1_999);
+        assert_eq!(Price::from_f64(0.00000001, &Currency::new("ICP", 8)).unwrap().amount, 1);
+        assert_eq!(Price::from_f64(3.0, &Currency::new("JPY", 0)).unwrap().amount, 3);
+    }
+
+    #[test]
+    fn prices_with_too_many_decimals_or_out_of_range_are_rejected() {
+        assert!(Price::from_f64(12.345, &usd()).is_err());
+        assert!(Price::from_f64(0.5, &Currency::new("JPY", 0)).is_err());
+        for value in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(Price::from_f64(value, &usd()).is_err());
+        }
+        assert_eq!(Price::from_f64(1e20, &usd()), Err("Price is too large.".to_string()));
+    }
+
+    #[test]
+    fn amounts_are_formatted_with_the_currency_decimals() {
+        assert_eq!(format_decimal(1_250, 2), "12.50");
+        assert_eq!(format_decimal(5, 2), "0.05");
+        assert_eq!(format_decimal(1, 8), "0.00000001");
+        assert_eq!(format_decimal(7, 0), "7");
+        assert_eq!(to_decimal(1_250, 2), 12.5);
+        assert_eq!(to_decimal(7, 0), 7.0);
+    }
+
+    #[test]
+    fn multiplication_keeps_the_currency_and_detects_overflow() {
+        let price = Price { amount: 250, currency: "USD".to_string() };
+        assert_eq!(price.checked_mul(4), Some(Price { amount: 1_000, currency: "USD".to_string() }));
+        assert_eq!(Price { amount: u64::MAX / 2, currency: "USD".to_string() }.checked_mul(3), None);
+    }
+}
@@ -0,0 +1,247 @@
+use candid::{decode_one, encode_one, CandidType, Deserialize};
+use serde::Serialize;
+use crate::attachment::AttachmentInput;
+use crate::chat::{Message, Reaction, ReadReceipt};
+use crate::notification::Notification;
+use crate::offer::Offer;
+
+// Bumped on every incompatible change to the events below
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Events sent by clients over WebSocket.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ClientEvent {
+    SendMessage {
+        conversation_id: String,
+        content: String,
+        client_id: Option<String>,
+        attachments: Option<Vec<AttachmentInput>>,
+        listing_id: Option<u64>,
+    },
+    SetTyping { conversation_id: String, is_typing: bool },
+    MarkRead { conversation_id: String, up_to_seq: Option<u64> },
+}
+
+/// Events pushed by the canister over WebSocket.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub enum ServerEvent {
+    Connected { user_id: String },
+    NewMessage { conversation_id: String, message: Message },
+    TypingStatus { conversation_id: String, user_id: String, is_typing: bool },
+    ReadReceipt { conversation_id: String, user_id: String, receipt: ReadReceipt },
+    OfferUpdated { conversation_id: String, offer: Offer },
+    MessageEdited { conversation_id: String, message: Message },
+    MessageDeleted { conversation_id: String, message_id: String, seq: u64 },
+    ReactionsUpdated { conversation_id: String, message_id: String, reactions: Vec<Reaction> },
+    Notification(Notification),
+    // Replies to SendMessage, `client_id` is echoed back so clients can match them to pending messages
+    MessageAck { conversation_id: String, client_id: Option<String>, message: Message },
+    MessageNack { conversation_id: String, client_id: Option<String>, reason: String },
+    Error { code: ErrorCode, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ErrorCode {
+    UnsupportedVersion, // the client should reload with a matching version
+    InvalidMessage,     // the payload could not be decoded
+    Rejected,           // the event was valid but not allowed, e.g. not a participant
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct ClientEnvelope {
+    pub version: u16,
+    pub event: ClientEvent,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ServerEnvelope {
+    pub version: u16,
+    pub event: ServerEvent,
+}
+
+impl ClientEnvelope {
+    /// Decodes a client payload, rejecting other protocol versions.
+    /// Failures carry the code and message of the `ServerEvent::Error` sent back to the client.
+    pub fn decode(bytes: &[u8]) -> Result<Self, (ErrorCode, String)> {
+        let envelope = decode_one::<ClientEnvelope>(bytes)
+            .map_err(|e| (ErrorCode::InvalidMessage, format!("Failed to decode message: {}", e)))?;
+
+        if envelope.version != PROTOCOL_VERSION {
+            return Err((
+                ErrorCode::UnsupportedVersion,
+                format!("Unsupported protocol version {}, expected {}", envelope.version, PROTOCOL_VERSION),
+            ));
+        }
+        Ok(envelope)
+    }
+}
+
+impl ServerEnvelope {
+    pub fn new(event: ServerEvent) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            event,
+        }
+    }
+
+    pub fn candid_serialize(&self) -> Vec<u8> {
+        encode_one(self).unwrap()
+    }
+}
+
+impl ServerEvent {
+    pub fn rejected(message: String) -> Self {
+        ServerEvent::Error {
+            code: ErrorCode::Rejected,
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::{Attachment, AttachmentKind};
+    use crate::notification::NotificationKind;
+    use crate::offer::OfferStatus;
+    use crate::price::Price;
+
+    fn message() -> Message {
+        Message {
+            id: "msg-1".to_string(),
+            seq: 1,
+            sender_id: "buyer".to_string(),
+            content: "Is this still available?".to_string(),
+            message_type: "image".to_string(),
+            attachments: vec![Attachment {
+                id: 3,
+                kind: AttachmentKind::Image,
+                file_name: "pallet.png".to_string(),
+                mime_type: "image/png".to_string(),
+                size: 1024,
+            }],
+            listing_id: Some(1),
+            offer_id: None,
+            timestamp: 10,
+            hidden: false,
+            client_id: Some("c-1".to_string()),
+            reactions: vec![Reaction { user_id: "seller".to_string(), emoji: "👍".to_string() }],
+            edited_at: Some(11),
+            deleted_at: None,
+            updated_at: Some(11),
+        }
+    }
+
+    fn offer() -> Offer {
+        Offer {
+            id: 4,
+            conversation_id: "conv-1".to_string(),
+            message_id: "msg-2".to_string(),
+            listing_id: 1,
+            from_id: "buyer".to_string(),
+            to_id: "seller".to_string(),
+            quantity: 10,
+            unit_price: Price { amount: 950, currency: "USD".to_string() },
+            total_price: Price { amount: 9_500, currency: "USD".to_string() },
+            status: OfferStatus::Pending,
+            created_at: 10,
+            expires_at: 20,
+            responded_at: None,
+            counter_offer_id: None,
+            order_id: None,
+        }
+    }
+
+    fn client_events() -> Vec<ClientEvent> {
+        vec![
+            ClientEvent::SendMessage {
+                conversation_id: "conv-1".to_string(),
+                content: "hello".to_string(),
+                client_id: Some("c-1".to_string()),
+                attachments: Some(vec![AttachmentInput {
+                    kind: AttachmentKind::File,
+                    file_name: "specs.pdf".to_string(),
+                    mime_type: "application/pdf".to_string(),
+                    data: "JVBERi0x".to_string(),
+                }]),
+                listing_id: Some(1),
+            },
+            ClientEvent::SetTyping { conversation_id: "conv-1".to_string(), is_typing: true },
+            ClientEvent::MarkRead { conversation_id: "conv-1".to_string(), up_to_seq: Some(3) },
+        ]
+    }
+
+    fn server_events() -> Vec<ServerEvent> {
+        let conversation_id = "conv-1".to_string();
+        vec![
+            ServerEvent::Connected { user_id: "buyer".to_string() },
+            ServerEvent::NewMessage { conversation_id: conversation_id.clone(), message: message() },
+            ServerEvent::TypingStatus { conversation_id: conversation_id.clone(), user_id: "seller".to_string(), is_typing: false },
+            ServerEvent::ReadReceipt {
+                conversation_id: conversation_id.clone(),
+                user_id: "seller".to_string(),
+                receipt: ReadReceipt { last_read_seq: 1, read_at: 12 },
+            },
+            ServerEvent::OfferUpdated { conversation_id: conversation_id.clone(), offer: offer() },
+            ServerEvent::MessageEdited { conversation_id: conversation_id.clone(), message: message() },
+            ServerEvent::MessageDeleted { conversation_id: conversation_id.clone(), message_id: "msg-1".to_string(), seq: 1 },
+            ServerEvent::ReactionsUpdated { conversation_id: conversation_id.clone(), message_id: "msg-1".to_string(), reactions: message().reactions },
+            ServerEvent::Notification(Notification {
+                id: 5,
+                user_id: "seller".to_string(),
+                kind: NotificationKind::NewMessage {
+                    conversation_id: conversation_id.clone(),
+                    message_id: "msg-1".to_string(),
+                    sender_id: "buyer".to_string(),
+                    preview: "Is this still available?".to_string(),
+                },
+                created_at: 10,
+                read: false,
+            }),
+            ServerEvent::MessageAck { conversation_id: conversation_id.clone(), client_id: Some("c-1".to_string()), message: message() },
+            ServerEvent::MessageNack { conversation_id, client_id: None, reason: "Message cannot be empty".to_string() },
+            ServerEvent::rejected("Not a participant in this conversation".to_string()),
+        ]
+    }
+
+    #[test]
+    fn client_events_round_trip() {
+        for event in client_events() {
+            let bytes = encode_one(ClientEnvelope { version: PROTOCOL_VERSION, event: event.clone() }).unwrap();
+            assert_eq!(ClientEnvelope::decode(&bytes).unwrap().event, event);
+        }
+    }
+
+    #[test]
+    fn server_events_round_trip() {
+        for event in server_events() {
+            let bytes = ServerEnvelope::new(event.clone()).candid_serialize();
+            let envelope = decode_one::<ServerEnvelope>(&bytes).unwrap();
+
+            assert_eq!(envelope.version, PROTOCOL_VERSION);
+            // ServerEvent carries types without PartialEq, their debug output is compared instead
+            assert_eq!(format!("{:?}", envelope.event), format!("{:?}", event));
+        }
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        for version in [0, PROTOCOL_VERSION + 1] {
+            let event = ClientEvent::MarkRead { conversation_id: "conv-1".to_string(), up_to_seq: None };
+            let bytes = encode_one(ClientEnvelope { version, event }).unwrap();
+
+            match ClientEnvelope::decode(&bytes) {
+                Err((code, _)) => assert_eq!(code, ErrorCode::UnsupportedVersion),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        match ClientEnvelope::decode(b"{\"message_type\":\"text\"}") {
+            Err((code, _)) => assert_eq!(code, ErrorCode::InvalidMessage),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
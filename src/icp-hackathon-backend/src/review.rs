@@ -5,6 +5,7 @@ pub struct Review {
     pub owner_id: String,
     pub rating: u8, //0-5
     pub comment: String,
+    pub hidden: bool,
 }
 
 impl Review {
@@ -13,6 +14,7 @@ impl Review {
             owner_id,
             rating,
             comment,
+            hidden: false,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+use candid::{CandidType, Deserialize};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use serde::Serialize;
+use crate::attribute::format_attributes;
+use crate::listing::Listing;
+use crate::price::{format_decimal, Currency};
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ListingRevision {
+    pub listing_id: u64,
+    pub revision: u32,
+    pub editor_id: String,
+    pub timestamp: u64,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RevisionHistory {
+    pub owner_id: String, // kept so the history stays reachable after the listing is purged
+    pub revisions: Vec<ListingRevision>,
+}
+
+thread_local! {
+    // Append-only, entries are never edited or removed
+    pub static REVISIONS: RefCell<HashMap<u64, RevisionHistory>> = RefCell::new(HashMap::new());
+}
+
+fn push_change(changes: &mut Vec<FieldChange>, field: &str, old_value: String, new_value: String) {
+    if old_value != new_value {
+        changes.push(FieldChange {
+            field: field.to_string(),
+            old_value,
+            new_value,
+        });
+    }
+}
+
+// Decimal amount with its currency, e.g. "12.50 USD"
+fn format_price(amount: u64, currency: &str, currencies: &[Currency]) -> String {
+    let decimals = currencies.iter().find(|c| c.code == currency).map_or(0, |c| c.decimals);
+    format!("{} {}", format_decimal(amount, decimals), currency)
+}
+
+fn format_tiers(listing: &Listing, currencies: &[Currency]) -> String {
+    listing
+        .price_tiers
+        .iter()
+        .map(|tier| format!("{}+: {}", tier.min_quantity, format_price(tier.unit_amount, &listing.price.currency, currencies)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn diff(before: &Listing, after: &Listing, currencies: &[Currency]) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+
+    push_change(&mut changes, "title", before.title.clone(), after.title.clone());
+    push_change(&mut changes, "description", before.description.clone(), after.description.clone());
+    push_change(&mut changes, "category", before.category.clone(), after.category.clone());
+    push_change(&mut changes, "categories_path", before.categories_path.clone(), after.categories_path.clone());
+    push_change(
+        &mut changes,
+        "price",
+        format_price(before.price.amount, &before.price.currency, currencies),
+        format_price(after.price.amount, &after.price.currency, currencies),
+    );
+    push_change(&mut changes, "min_order_quantity", before.min_order_quantity.to_string(), after.min_order_quantity.to_string());
+    push_change(&mut changes, "price_tiers", format_tiers(before, currencies), format_tiers(after, currencies));
+    push_change(&mut changes, "attributes", format_attributes(&before.attributes), format_attributes(&after.attributes));
+    push_change(&mut changes, "amount", before.amount.to_string(), after.amount.to_string());
+    push_change(&mut changes, "images_id", format!("{:?}", before.images_id), format!("{:?}", after.images_id));
+
+    changes
+}
+
+/// Appends a revision if the edit changed anything.
+pub fn record(before: &Listing, after: &Listing, editor_id: &str, currencies: &[Currency], now: u64) {
+    let changes = diff(before, after, currencies);
+    if changes.is_empty() {
+        return;
+    }
+
+    REVISIONS.with(|revisions| {
+        let mut revisions = revisions.borrow_mut();
+        let history = revisions.entry(after.id).or_insert_with(|| RevisionHistory {
+            owner_id: after.owner_id.clone(),
+            revisions: Vec::new(),
+        });
+
+        history.revisions.push(ListingRevision {
+            listing_id: after.id,
+            revision: history.revisions.len() as u32 + 1,
+            editor_id: editor_id.to_string(),
+            timestamp: now,
+            changes,
+        });
+    });
+}
+
+pub fn get_revisions(listing_id: u64) -> Vec<ListingRevision> {
+    REVISIONS.with(|revisions| {
+        revisions.borrow().get(&listing_id).map(|history| history.revisions.clone()).unwrap_or_default()
+    })
+}
+
+pub fn get_owner(listing_id: u64) -> Option<String> {
+    REVISIONS.with(|revisions| revisions.borrow().get(&listing_id).map(|history| history.owner_id.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::tests::listing;
+    use crate::price::{Price, PriceTier};
+
+    fn currencies() -> Vec<Currency> {
+        vec![Currency::new("USD", 2), Currency::new("ICP", 8)]
+    }
+
+    #[test]
+    fn prices_are_shown_as_decimals() {
+        let before = listing(5);
+        let mut after = before.clone();
+        after.price = Price { amount: 1_250, currency: "USD".to_string() };
+        after.price_tiers = vec![PriceTier { min_quantity: 10, unit_amount: 999 }];
+
+        let changes = diff(&before, &after, &currencies());
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].old_value.as_str(), changes[0].new_value.as_str()), ("10.00 USD", "12.50 USD"));
+        assert_eq!(changes[1].new_value, "10+: 9.99 USD");
+    }
+
+    #[test]
+    fn only_changing_edits_are_recorded() {
+        let before = listing(5);
+        record(&before, &before.clone(), "seller", &currencies(), 1);
+        assert!(get_revisions(before.id).is_empty());
+
+        let mut after = before.clone();
+        after.title = "Pallet of red bricks".to_string();
+        record(&before, &after, "seller", &currencies(), 2);
+        after.set_amount(3);
+        record(&before, &after, "moderator", &currencies(), 3);
+
+        let revisions = get_revisions(before.id);
+        assert_eq!(revisions.iter().map(|r| r.revision).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(revisions[0].changes[0].field, "title");
+        assert_eq!(revisions[1].editor_id, "moderator");
+        assert_eq!(revisions[1].timestamp, 3);
+        assert_eq!(get_owner(before.id).as_deref(), Some("seller"));
+    }
+}
@@ -0,0 +1,214 @@
+use candid::{CandidType, Deserialize};
+use crate::clock::time;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use crate::listing::Listing;
+use crate::price::PriceRange;
+use crate::notification::{notify, NotificationKind};
+
+static AMOUNT_OF_SAVED_SEARCHES: AtomicU64 = AtomicU64::new(0);
+
+pub const MAX_SAVED_SEARCHES_PER_USER: usize = 20;
+pub const MAX_KEYWORDS: usize = 10;
+pub const MAX_NAME_LEN: usize = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct SavedSearch {
+    pub id: u64,
+    pub owner_id: String,
+    pub name: String,
+    pub categories_path: Option<String>, // e.g. "Electronics" matches "Electronics/Laptops"
+    pub keywords: Vec<String>,
+    pub price_range: Option<PriceRange>,
+    pub created_at: u64,
+}
+
+thread_local! {
+    pub static SAVED_SEARCHES: RefCell<Vec<SavedSearch>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn saved_searches_count() -> u64 {
+    AMOUNT_OF_SAVED_SEARCHES.load(Ordering::SeqCst)
+}
+
+/// Restores the id counter after an upgrade.
+pub fn restore_saved_searches_count(count: u64) {
+    AMOUNT_OF_SAVED_SEARCHES.store(count, Ordering::SeqCst);
+}
+
+impl SavedSearch {
+    pub fn new(
+        owner_id: String,
+        name: String,
+        categories_path: Option<String>,
+        keywords: Vec<String>,
+        price_range: Option<PriceRange>,
+    ) -> Self {
+        Self {
+            id: AMOUNT_OF_SAVED_SEARCHES.fetch_add(1, Ordering::SeqCst),
+            owner_id,
+            name,
+            categories_path,
+            keywords: keywords.iter().map(|k| k.trim().to_lowercase()).filter(|k| !k.is_empty()).collect(),
+            price_range,
+            created_at: time(),
+        }
+    }
+
+    pub fn matches(&self, listing: &Listing) -> bool {
+        if listing.owner_id == self.owner_id {
+            return false;
+        }
+
+        if self.categories_path.as_ref().is_some_and(|path| !listing.in_category(path)) {
+            return false;
+        }
+        if self.price_range.as_ref().is_some_and(|range| !range.contains(&listing.price)) {
+            return false;
+        }
+
+        let text = format!("{} {}", listing.title, listing.description).to_lowercase();
+        self.keywords.iter().all(|keyword| text.contains(keyword.as_str()))
+    }
+}
+
+pub fn add_saved_search(search: SavedSearch) -> Result<SavedSearch, String> {
+    if search.name.len() > MAX_NAME_LEN {
+        return Err("Search name is too long!".to_string());
+    }
+    if search.keywords.len() > MAX_KEYWORDS {
+        return Err(format!("A search can have at most {} keywords.", MAX_KEYWORDS));
+    }
+    if let Some(range) = &search.price_range {
+        range.validate()?;
+    }
+    if search.categories_path.is_none() && search.keywords.is_empty() && search.price_range.is_none() {
+        return Err("A saved search needs at least one criterion.".to_string());
+    }
+
+    SAVED_SEARCHES.with(|searches| {
+        let mut searches = searches.borrow_mut();
+
+        let owned = searches.iter().filter(|s| s.owner_id == search.owner_id).count();
+        if owned >= MAX_SAVED_SEARCHES_PER_USER {
+            return Err(format!("You can save at most {} searches.", MAX_SAVED_SEARCHES_PER_USER));
+        }
+
+        searches.push(search.clone());
+        Ok(search)
+    })
+}
+
+pub fn get_user_saved_searches(user_id: &str) -> Vec<SavedSearch> {
+    SAVED_SEARCHES.with(|searches| {
+        searches.borrow().iter().filter(|s| s.owner_id == user_id).cloned().collect()
+    })
+}
+
+pub fn remove_saved_search(id: u64, user_id: &str) -> Result<(), String> {
+    SAVED_SEARCHES.with(|searches| {
+        let mut searches = searches.borrow_mut();
+
+        if let Some(index) = searches.iter().position(|s| s.id == id) {
+            if searches[index].owner_id != user_id {
+                return Err("Permission denied: You are not the owner of this search.".to_string());
+            }
+            searches.remove(index);
+            Ok(())
+        } else {
+            Err("Saved search not found.".to_string())
+        }
+    })
+}
+
+/// Notifies the owners of every saved search the new listing matches, once per user.
+pub fn notify_matching_searches(listing: &Listing) {
+    let matches: Vec<SavedSearch> = SAVED_SEARCHES.with(|searches| {
+        searches.borrow().iter().filter(|s| s.matches(listing)).cloned().collect()
+    });
+
+    let mut notified: Vec<String> = Vec::new();
+    for search in matches {
+        if notified.contains(&search.owner_id) {
+            continue;
+        }
+
+        notify(&search.owner_id, NotificationKind::SavedSearchMatch {
+            search_id: search.id,
+            search_name: search.name.clone(),
+            listing_id: listing.id,
+            listing_title: listing.title.clone(),
+        });
+        notified.push(search.owner_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::get_notifications;
+    use crate::order::tests::listing;
+
+    fn search(owner_id: &str, categories_path: Option<&str>, keywords: &[&str], price_range: Option<PriceRange>) -> SavedSearch {
+        SavedSearch::new(
+            owner_id.to_string(),
+            "Bricks".to_string(),
+            categories_path.map(|path| path.to_string()),
+            keywords.iter().map(|keyword| keyword.to_string()).collect(),
+            price_range,
+        )
+    }
+
+    fn usd(min: Option<u64>, max: Option<u64>) -> Option<PriceRange> {
+        Some(PriceRange { currency: "USD".to_string(), min, max })
+    }
+
+    #[test]
+    fn searches_match_on_every_criterion() {
+        let mut listing = listing(5);
+        listing.categories_path = "Construction/Bricks".to_string();
+
+        assert!(search("buyer", Some("Construction"), &[" RED ", "pallet"], usd(Some(500), Some(1_000))).matches(&listing));
+        assert!(!search("buyer", Some("Construction/Tiles"), &[], None).matches(&listing));
+        assert!(!search("buyer", None, &["red", "yellow"], None).matches(&listing));
+        assert!(!search("buyer", None, &[], usd(None, Some(999))).matches(&listing));
+        assert!(!search("buyer", None, &[], Some(PriceRange { currency: "ICP".to_string(), min: None, max: None })).matches(&listing));
+        // Sellers are not told about their own listings
+        assert!(!search("seller", Some("Construction"), &[], None).matches(&listing));
+    }
+
+    #[test]
+    fn searches_need_a_valid_criterion() {
+        assert!(add_saved_search(search("buyer", None, &[" "], None)).is_err());
+        assert!(add_saved_search(search("buyer", None, &[], usd(Some(2), Some(1)))).is_err());
+        let keywords: Vec<String> = (0..=MAX_KEYWORDS).map(|i| format!("keyword{}", i)).collect();
+        let keywords: Vec<&str> = keywords.iter().map(|keyword| keyword.as_str()).collect();
+        assert!(add_saved_search(search("buyer", None, &keywords, None)).is_err());
+
+        for _ in 0..MAX_SAVED_SEARCHES_PER_USER {
+            add_saved_search(search("buyer", None, &["bricks"], None)).unwrap();
+        }
+        assert!(add_saved_search(search("buyer", None, &["bricks"], None)).is_err());
+        assert!(add_saved_search(search("other", None, &["bricks"], None)).is_ok());
+    }
+
+    #[test]
+    fn matching_users_are_notified_once() {
+        add_saved_search(search("buyer", Some("Construction"), &[], None)).unwrap();
+        add_saved_search(search("buyer", None, &["bricks"], None)).unwrap();
+        add_saved_search(search("seller", None, &["bricks"], None)).unwrap();
+        add_saved_search(search("other", None, &["tiles"], None)).unwrap();
+
+        notify_matching_searches(&listing(5));
+
+        let notifications = get_notifications("buyer", false, 0, 10);
+        assert_eq!(notifications.len(), 1);
+        match &notifications[0].kind {
+            NotificationKind::SavedSearchMatch { listing_id, .. } => assert_eq!(*listing_id, 1),
+            other => panic!("unexpected notification {:?}", other),
+        }
+        assert!(get_notifications("seller", false, 0, 10).is_empty());
+        assert!(get_notifications("other", false, 0, 10).is_empty());
+    }
+}
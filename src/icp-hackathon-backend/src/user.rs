@@ -1,5 +1,5 @@
 use candid::{CandidType, Deserialize};
-use ic_cdk::api::{time};
+use crate::clock::time;
 use serde::Serialize;
 
 #[derive(Clone, CandidType, Deserialize, Debug, Serialize)]
@@ -25,5 +25,29 @@ impl User {
             favorites_id: None,
         }
     }
-}
 
+    /// Adds a listing to favorites, returns false if it was already there.
+    pub fn add_favorite(&mut self, listing_id: u64) -> bool {
+        let favorites = self.favorites_id.get_or_insert_with(Vec::new);
+        if favorites.contains(&listing_id) {
+            return false;
+        }
+        favorites.push(listing_id);
+        true
+    }
+
+    /// Removes a listing from favorites, returns false if it was not there.
+    pub fn remove_favorite(&mut self, listing_id: u64) -> bool {
+        if let Some(favorites) = self.favorites_id.as_mut() {
+            if let Some(index) = favorites.iter().position(|id| *id == listing_id) {
+                favorites.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn is_favorite(&self, listing_id: u64) -> bool {
+        self.favorites_id.as_ref().is_some_and(|favorites| favorites.contains(&listing_id))
+    }
+}
@@ -6,6 +6,9 @@ import useImage from "../../common/hooks/useImage.js";
 import useFavorite from "../../common/hooks/useFavorite.js";
 import useCalculateAvgReview from "../../common/hooks/useCalculateAvgReview.js";
 
+// utilities
+import { formatPrice } from "../../common/price.js";
+
 // components
 import Button from "../../common/Button";
 import Loader from "../../common/components/Loader/Loader.jsx";
@@ -37,7 +40,7 @@ function List({ listings }) {
 
 function ListItem({ id, images, title, description, price, reviews }) {
 	const [imgLoading, img] = useImage(images[0]);
-	const formattedPrice = new Intl.NumberFormat("pl-PL", { style: "currency", currency: "USD" }).format(price);
+	const formattedPrice = formatPrice(price);
 	const shortDescription = description.split(" ").slice(0, 20).join(" ") + "...";
 	const avgRating = useCalculateAvgReview(id);
 
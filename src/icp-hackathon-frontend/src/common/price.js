@@ -0,0 +1,16 @@
+// Must match the currencies in the backend config.rs, prices are sent as amounts in the smallest unit
+const CURRENCY_DECIMALS = { USD: 2, EUR: 2, PLN: 2, ICP: 8, ckBTC: 8 };
+
+// Decimal value of a backend Price record, e.g. { amount: 1250n, currency: "USD" } -> 12.5
+export const priceToDecimal = ({ amount, currency }) => Number(amount) / 10 ** (CURRENCY_DECIMALS[currency] ?? 0);
+
+export const formatPrice = price => {
+	const value = priceToDecimal(price);
+	try {
+		return new Intl.NumberFormat("pl-PL", { style: "currency", currency: price.currency }).format(value);
+	} catch {
+		// Token symbols such as ckBTC are not ISO 4217 codes
+		const digits = CURRENCY_DECIMALS[price.currency] ?? 0;
+		return `${new Intl.NumberFormat("pl-PL", { maximumFractionDigits: digits }).format(value)} ${price.currency}`;
+	}
+};
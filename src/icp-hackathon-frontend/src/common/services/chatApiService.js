@@ -102,7 +102,8 @@ class ChatApiService {
 		}
 	}
 
-	async sendMessage(conversationId, content) {
+	// `clientId` lets the backend return the already stored message when a send is retried
+	async sendMessage(conversationId, content, clientId = null) {
 		if (!this.actor) {
 			throw new Error("Chat API not initialized");
 		}
@@ -126,7 +127,13 @@ class ChatApiService {
 		}
 
 		try {
-			const result = await this.actor.send_chat_message(conversationId, content);
+			const result = await this.actor.send_chat_message(
+				conversationId,
+				content,
+				clientId ? [clientId] : [],
+				[], // attachments
+				[] // listing_id
+			);
 			if ("Ok" in result) {
 				// Message broadcasting is now handled automatically by the backend
 				return result.Ok;
@@ -166,13 +173,14 @@ class ChatApiService {
 		}
 	}
 
+	// Conversation summaries, most recently active first
 	async getUserConversations() {
 		if (!this.actor) {
 			throw new Error("Chat API not initialized");
 		}
 
 		try {
-			return await this.actor.get_user_conversations();
+			return await this.actor.get_conversation_summaries();
 		} catch (error) {
 			console.error("Failed to get user conversations:", error);
 			throw error;
@@ -185,9 +193,10 @@ class ChatApiService {
 		}
 
 		try {
-			const result = await this.actor.mark_conversation_read(conversationId);
+			// Without up_to_seq everything up to the latest message is marked as read
+			const result = await this.actor.mark_conversation_read(conversationId, []);
 			if ("Ok" in result) {
-				return true;
+				return result.Ok;
 			} else {
 				throw new Error(result.Err);
 			}
@@ -197,6 +206,20 @@ class ChatApiService {
 		}
 	}
 
+	// Conversations changed since `since` (server time in nanoseconds), with their read receipts
+	async syncConversations(since) {
+		if (!this.actor) {
+			throw new Error("Chat API not initialized");
+		}
+
+		try {
+			return await this.actor.sync_conversations(since);
+		} catch (error) {
+			console.error("Failed to sync conversations:", error);
+			throw error;
+		}
+	}
+
 	async setTypingStatus(conversationId, isTyping) {
 		if (!this.actor) {
 			throw new Error("Chat API not initialized");
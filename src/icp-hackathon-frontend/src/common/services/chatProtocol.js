@@ -0,0 +1,151 @@
+import { IDL } from "@dfinity/candid";
+
+// Must match PROTOCOL_VERSION in the backend protocol.rs, the canister rejects other versions
+export const PROTOCOL_VERSION = 1;
+
+// Candid types of the WebSocket envelopes, mirroring icp-hackathon-backend.did
+const AttachmentKind = IDL.Variant({ Image: IDL.Null, File: IDL.Null });
+const Attachment = IDL.Record({
+	id: IDL.Nat64,
+	kind: AttachmentKind,
+	file_name: IDL.Text,
+	mime_type: IDL.Text,
+	size: IDL.Nat64
+});
+const AttachmentInput = IDL.Record({
+	kind: AttachmentKind,
+	file_name: IDL.Text,
+	mime_type: IDL.Text,
+	data: IDL.Text
+});
+const Reaction = IDL.Record({ user_id: IDL.Text, emoji: IDL.Text });
+const Message = IDL.Record({
+	id: IDL.Text,
+	seq: IDL.Nat64,
+	sender_id: IDL.Text,
+	content: IDL.Text,
+	message_type: IDL.Text,
+	attachments: IDL.Vec(Attachment),
+	listing_id: IDL.Opt(IDL.Nat64),
+	offer_id: IDL.Opt(IDL.Nat64),
+	timestamp: IDL.Nat64,
+	hidden: IDL.Bool,
+	client_id: IDL.Opt(IDL.Text),
+	reactions: IDL.Vec(Reaction),
+	edited_at: IDL.Opt(IDL.Nat64),
+	deleted_at: IDL.Opt(IDL.Nat64),
+	updated_at: IDL.Opt(IDL.Nat64)
+});
+const ReadReceipt = IDL.Record({ last_read_seq: IDL.Nat64, read_at: IDL.Nat64 });
+const Price = IDL.Record({ amount: IDL.Nat64, currency: IDL.Text });
+const OfferStatus = IDL.Variant({
+	Pending: IDL.Null,
+	Accepted: IDL.Null,
+	Declined: IDL.Null,
+	Countered: IDL.Null,
+	Expired: IDL.Null
+});
+const Offer = IDL.Record({
+	id: IDL.Nat64,
+	conversation_id: IDL.Text,
+	message_id: IDL.Text,
+	listing_id: IDL.Nat64,
+	from_id: IDL.Text,
+	to_id: IDL.Text,
+	quantity: IDL.Nat32,
+	unit_price: Price,
+	total_price: Price,
+	status: OfferStatus,
+	created_at: IDL.Nat64,
+	expires_at: IDL.Nat64,
+	responded_at: IDL.Opt(IDL.Nat64),
+	counter_offer_id: IDL.Opt(IDL.Nat64),
+	order_id: IDL.Opt(IDL.Nat64)
+});
+const OrderStatus = IDL.Variant({
+	Requested: IDL.Null,
+	Accepted: IDL.Null,
+	Rejected: IDL.Null,
+	Shipped: IDL.Null,
+	Completed: IDL.Null,
+	Cancelled: IDL.Null
+});
+const ReportTarget = IDL.Variant({
+	Listing: IDL.Nat64,
+	Review: IDL.Record({ listing_id: IDL.Nat64, owner_id: IDL.Text }),
+	User: IDL.Text,
+	ChatMessage: IDL.Record({ conversation_id: IDL.Text, message_id: IDL.Text })
+});
+const ModerationAction = IDL.Variant({ Hide: IDL.Null, Delete: IDL.Null, Dismiss: IDL.Null, BanAuthor: IDL.Null });
+const NotificationKind = IDL.Variant({
+	SavedSearchMatch: IDL.Record({
+		search_id: IDL.Nat64,
+		search_name: IDL.Text,
+		listing_id: IDL.Nat64,
+		listing_title: IDL.Text
+	}),
+	NewMessage: IDL.Record({
+		conversation_id: IDL.Text,
+		message_id: IDL.Text,
+		sender_id: IDL.Text,
+		preview: IDL.Text
+	}),
+	NewReview: IDL.Record({ listing_id: IDL.Nat64, reviewer_id: IDL.Text, rating: IDL.Nat8 }),
+	ListingFavorited: IDL.Record({ listing_id: IDL.Nat64, user_id: IDL.Text }),
+	Moderation: IDL.Record({ target: ReportTarget, action: ModerationAction }),
+	ListingExpired: IDL.Record({ listing_id: IDL.Nat64, listing_title: IDL.Text }),
+	OrderUpdated: IDL.Record({ order_id: IDL.Nat64, listing_id: IDL.Nat64, status: OrderStatus }),
+	OfferUpdated: IDL.Record({ offer_id: IDL.Nat64, conversation_id: IDL.Text, status: OfferStatus })
+});
+const Notification = IDL.Record({
+	id: IDL.Nat64,
+	user_id: IDL.Text,
+	kind: NotificationKind,
+	created_at: IDL.Nat64,
+	read: IDL.Bool
+});
+
+const ClientEvent = IDL.Variant({
+	SendMessage: IDL.Record({
+		conversation_id: IDL.Text,
+		content: IDL.Text,
+		client_id: IDL.Opt(IDL.Text),
+		attachments: IDL.Opt(IDL.Vec(AttachmentInput)),
+		listing_id: IDL.Opt(IDL.Nat64)
+	}),
+	SetTyping: IDL.Record({ conversation_id: IDL.Text, is_typing: IDL.Bool }),
+	MarkRead: IDL.Record({ conversation_id: IDL.Text, up_to_seq: IDL.Opt(IDL.Nat64) })
+});
+// Exported for WebSocket libraries that need the outgoing type, e.g. to encode raw bytes
+export const ClientEnvelope = IDL.Record({ version: IDL.Nat16, event: ClientEvent });
+
+const ErrorCode = IDL.Variant({ UnsupportedVersion: IDL.Null, InvalidMessage: IDL.Null, Rejected: IDL.Null });
+const ServerEvent = IDL.Variant({
+	Connected: IDL.Record({ user_id: IDL.Text }),
+	NewMessage: IDL.Record({ conversation_id: IDL.Text, message: Message }),
+	TypingStatus: IDL.Record({ conversation_id: IDL.Text, user_id: IDL.Text, is_typing: IDL.Bool }),
+	ReadReceipt: IDL.Record({ conversation_id: IDL.Text, user_id: IDL.Text, receipt: ReadReceipt }),
+	OfferUpdated: IDL.Record({ conversation_id: IDL.Text, offer: Offer }),
+	MessageEdited: IDL.Record({ conversation_id: IDL.Text, message: Message }),
+	MessageDeleted: IDL.Record({ conversation_id: IDL.Text, message_id: IDL.Text, seq: IDL.Nat64 }),
+	ReactionsUpdated: IDL.Record({ conversation_id: IDL.Text, message_id: IDL.Text, reactions: IDL.Vec(Reaction) }),
+	Notification: Notification,
+	MessageAck: IDL.Record({ conversation_id: IDL.Text, client_id: IDL.Opt(IDL.Text), message: Message }),
+	MessageNack: IDL.Record({ conversation_id: IDL.Text, client_id: IDL.Opt(IDL.Text), reason: IDL.Text }),
+	Error: IDL.Record({ code: ErrorCode, message: IDL.Text })
+});
+const ServerEnvelope = IDL.Record({ version: IDL.Nat16, event: ServerEvent });
+
+export const clientEnvelope = event => ({ version: PROTOCOL_VERSION, event });
+
+// Accepts raw bytes, or an envelope the WebSocket library already decoded
+export const decodeServerEnvelope = data => {
+	if (data instanceof ArrayBuffer || ArrayBuffer.isView(data)) {
+		const bytes = data instanceof ArrayBuffer ? new Uint8Array(data) : new Uint8Array(data.buffer, data.byteOffset, data.byteLength);
+		return IDL.decode([ServerEnvelope], bytes)[0];
+	}
+	return data;
+};
+
+// Splits a candid variant into its tag and payload, e.g. { NewMessage: {...} } -> ["NewMessage", {...}]
+export const variantEntry = variant => Object.entries(variant)[0];
@@ -1,6 +1,7 @@
 // import { IcWebSocket } from "ic-websocket-js";
 import { Actor, HttpAgent } from "@dfinity/agent";
 import { idlFactory } from "../../../../declarations/icp-hackathon-backend/icp-hackathon-backend.did.js";
+import { PROTOCOL_VERSION, clientEnvelope, decodeServerEnvelope, variantEntry } from "./chatProtocol.js";
 
 class ChatWebSocketService {
 	constructor() {
@@ -134,59 +135,66 @@ class ChatWebSocketService {
 		}, delay);
 	}
 
+	// Messages are candid-encoded ServerEnvelope values, see protocol.rs in the backend
 	handleIncomingMessage(rawData) {
 		try {
-			console.log("Raw WebSocket message received:", rawData);
-
-			// The message should be a Candid-encoded message
-			// For now, let's try to parse it as different message types
-			if (typeof rawData === "string") {
-				const message = JSON.parse(rawData);
-				this.processMessage(message);
-			} else {
-				// It's likely a Candid-encoded message, we'd need to decode it
-				console.log("Received binary message, attempting to decode...");
-				// For now, just log it
+			const envelope = decodeServerEnvelope(rawData);
+			if (envelope.version !== PROTOCOL_VERSION) {
+				console.error(`Unsupported chat protocol version ${envelope.version}, expected ${PROTOCOL_VERSION}`);
+				this.notifyConnectionHandlers({ type: "version_mismatch", version: envelope.version });
+				return;
 			}
+			this.processEvent(envelope.event);
 		} catch (error) {
-			console.error("Failed to parse incoming message:", error, rawData);
+			console.error("Failed to decode incoming message:", error, rawData);
 		}
 	}
 
-	processMessage(message) {
-		console.log("Processing message:", message);
+	processEvent(event) {
+		const [type, data] = variantEntry(event);
 
-		switch (message.message_type) {
-			case "new_message":
+		switch (type) {
+			case "NewMessage":
+			case "MessageAck":
+			case "MessageEdited":
+				// Acks carry the stored message, the store replaces the optimistic one by content or id
 				this.notifyMessageHandlers({
-					type: "new_message",
-					conversationId: message.conversation_id,
-					message: {
-						id: Date.now() + Math.random(), // Generate a temporary ID
-						sender_id: message.sender_id,
-						content: message.content,
-						timestamp: message.timestamp,
-						message_type: "text",
-						read: false
-					}
+					type: type === "MessageEdited" ? "message_edited" : "new_message",
+					conversationId: data.conversation_id,
+					message: data.message
 				});
 				break;
 
-			case "typing_status":
+			case "MessageNack":
+				console.error("Message rejected:", data.reason);
+				this.notifyMessageHandlers({
+					type: "message_rejected",
+					conversationId: data.conversation_id,
+					clientId: data.client_id[0] ?? null,
+					reason: data.reason
+				});
+				break;
+
+			case "TypingStatus":
 				this.notifyTypingHandlers({
 					type: "typing_status",
-					conversationId: message.conversation_id,
-					userId: message.user_id,
-					isTyping: message.is_typing
+					conversationId: data.conversation_id,
+					userId: data.user_id,
+					isTyping: data.is_typing
 				});
 				break;
 
-			case "connection_established":
-				console.log("Connection established with backend");
+			case "Connected":
+				console.log("Connection established with backend as", data.user_id);
+				break;
+
+			case "Error":
+				console.error("Chat error:", variantEntry(data.code)[0], data.message);
 				break;
 
 			default:
-				console.log("Unknown message type:", message.message_type);
+				// ReadReceipt, MessageDeleted, ReactionsUpdated, OfferUpdated and Notification are picked up by sync
+				console.log("Unhandled chat event:", type, data);
 		}
 	}
 
@@ -236,25 +244,27 @@ class ChatWebSocketService {
 		});
 	}
 
-	// Send message via WebSocket
-	sendMessage(conversationId, content) {
+	send(event) {
+		// The gateway library candid-encodes the envelope with the ws_message argument type
+		this.ws.send(clientEnvelope(event));
+	}
+
+	// Send message via WebSocket, the reply is a MessageAck or MessageNack echoing `clientId`
+	sendMessage(conversationId, content, clientId = null) {
 		if (!this.isConnected || !this.ws) {
 			throw new Error("WebSocket not connected");
 		}
 
-		const message = {
-			message_type: "chat_message",
-			data: JSON.stringify({
-				message_type: "chat_message",
-				conversation_id: conversationId,
-				sender_id: this.identity.getPrincipal().toString(),
-				content: content,
-				timestamp: Date.now() * 1000000 // Convert to nanoseconds
-			})
-		};
-
 		try {
-			this.ws.send(message);
+			this.send({
+				SendMessage: {
+					conversation_id: conversationId,
+					content: content,
+					client_id: clientId ? [clientId] : [],
+					attachments: [],
+					listing_id: []
+				}
+			});
 		} catch (error) {
 			console.error("Failed to send message via WebSocket:", error);
 			throw error;
@@ -268,17 +278,8 @@ class ChatWebSocketService {
 			return;
 		}
 
-		const message = {
-			message_type: "typing_status",
-			data: JSON.stringify({
-				conversation_id: conversationId,
-				user_id: this.identity.getPrincipal().toString(),
-				is_typing: isTyping
-			})
-		};
-
 		try {
-			this.ws.send(message);
+			this.send({ SetTyping: { conversation_id: conversationId, is_typing: isTyping } });
 		} catch (error) {
 			console.error("Failed to send typing status via WebSocket:", error);
 		}
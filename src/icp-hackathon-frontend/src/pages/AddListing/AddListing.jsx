@@ -106,7 +106,11 @@ function AddListing() {
 				price,
 				amount,
 				images.map(img => img.substring(img.indexOf(",") + 1)),
-				category.join("/")
+				category.join("/"),
+				// Optional currency, wholesale pricing and attributes, the price is in the default currency (USD)
+				[],
+				[],
+				[]
 			);
 			if (Ok) {
 				const parsedListing = parseBackendListing(Ok);
@@ -48,6 +48,8 @@ function MessageItem({
 	const isOwn = senderId === user?.id;
 	const isOptimistic = message.isOptimistic;
 	const isFailed = message.failed;
+	// Own messages are read once the other participant's last read seq reaches them
+	const isRead = Number(message.seq ?? 0) > 0 && Number(message.seq) <= (conversation?.readReceipts?.[conversation.otherUserId] ?? 0);
 
 	const shouldShowDateSeparator = (currentMessage, previousMessage) => {
 		if (!previousMessage) return true;
@@ -95,10 +97,10 @@ function MessageItem({
 					{showTimestamps && (
 						<div className="message-time">
 							{formatMessageTime(message.timestamp)}
-							{isOwn && !isOptimistic && !isFailed && message.read && (
+							{isOwn && !isOptimistic && !isFailed && isRead && (
 								<i className="fas fa-check-double read-receipt"></i>
 							)}
-							{isOwn && !isOptimistic && !isFailed && !message.read && (
+							{isOwn && !isOptimistic && !isFailed && !isRead && (
 								<i className="fas fa-check sent-receipt"></i>
 							)}
 							{isOwn && isOptimistic && <i className="fas fa-clock pending-receipt" title="Sending..."></i>}
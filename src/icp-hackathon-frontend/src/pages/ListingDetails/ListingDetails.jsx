@@ -7,6 +7,9 @@ import useImage from "../../common/hooks/useImage.js";
 import useListing from "../../common/hooks/useListing.js";
 import useCalculateAvgReview from "../../common/hooks/useCalculateAvgReview.js";
 
+// utilities
+import { formatPrice } from "../../common/price.js";
+
 // components
 import Button from "../../common/Button";
 import Empty from "../../common/components/Empty/Empty.jsx";
@@ -35,7 +38,7 @@ function ListingDetails() {
 	const { title, description, price, reviews, ownerId, category, ...rest } = listing ?? {};
 	const [imagesLoading, ...images] = useImage(...(rest?.images ?? []));
 
-	const formattedPrice = new Intl.NumberFormat("pl-PL", { style: "currency", currency: "USD" }).format(price);
+	const formattedPrice = price ? formatPrice(price) : "";
 	const favorite = false;
 	const avgRating = useCalculateAvgReview(+productId);
 
@@ -2,6 +2,30 @@ import { create } from "zustand";
 import { chatApiService } from "../common/services/chatApiService.js";
 import { chatWebSocketService } from "../common/services/chatWebSocketService.js";
 
+const toIsoDate = nanoseconds => new Date(Number(nanoseconds) / 1000000).toISOString();
+
+// Backend read receipts are [userId, { last_read_seq, read_at }] pairs, kept as userId -> last read seq
+const toReadReceipts = receipts =>
+	receipts.reduce((acc, [userId, receipt]) => {
+		acc[userId] = Number(receipt.last_read_seq);
+		return acc;
+	}, {});
+
+// Conversation lists are loaded as summaries, messages are fetched per conversation
+const summaryToConversation = (summary, userId) => ({
+	...summary,
+	messages: [],
+	lastMessage: summary.last_message_preview.length
+		? { senderId: summary.last_message_sender[0], content: summary.last_message_preview[0] }
+		: null,
+	lastMessageTime: summary.last_message_time.length ? toIsoDate(summary.last_message_time[0]) : null,
+	createdAt: toIsoDate(summary.created_at),
+	unreadCount: summary.unread_count,
+	readReceipts: {}, // filled in by syncReadReceipts
+	otherUserId: summary.participants.find(p => p !== userId) || "unknown",
+	typingUsers: {}
+});
+
 const useStore = create(set => ({
 	user: null,
 	setUser: user => set({ user, loadingUser: false, userCreating: false }),
@@ -68,6 +92,7 @@ const useStore = create(set => ({
 	chatInitialized: false,
 	chatLoading: false,
 	chatError: null,
+	chatSyncedAt: 0n, // server time of the last sync_conversations call
 
 	// Initialize chat services
 	initializeChat: async () => {
@@ -86,6 +111,7 @@ const useStore = create(set => ({
 				chatWebSocketService.onMessage(data => {
 					console.log("WebSocket message received:", data);
 					const { conversationId, message } = data;
+					if (!message) return; // rejected sends carry no message
 
 					// Add the message to the store, avoiding duplicates
 					set(state => {
@@ -170,19 +196,8 @@ const useStore = create(set => ({
 
 			// Load initial conversations
 			const conversations = await chatApiService.getUserConversations();
-			const conversationsMap = conversations.reduce((acc, conv) => {
-				acc[conv.id] = {
-					...conv,
-					messages: conv.messages || [],
-					lastMessage: conv.messages && conv.messages.length > 0 ? conv.messages[conv.messages.length - 1] : null,
-					lastMessageTime: conv.last_message_time
-						? new Date(Number(conv.last_message_time) / 1000000).toISOString()
-						: null,
-					createdAt: new Date(Number(conv.created_at) / 1000000).toISOString(),
-					unreadCount: conv.unread_counts[useStore.getState().user?.id] || 0,
-					otherUserId: conv.participants.find(p => p !== useStore.getState().user?.id),
-					typingUsers: {}
-				};
+			const conversationsMap = conversations.reduce((acc, summary) => {
+				acc[summary.id] = summaryToConversation(summary, useStore.getState().user?.id);
 				return acc;
 			}, {});
 
@@ -219,7 +234,7 @@ const useStore = create(set => ({
 				content: message.content,
 				type: message.message_type,
 				timestamp: new Date(Number(message.timestamp) / 1000000).toISOString(),
-				read: message.read
+				seq: Number(message.seq)
 			};
 
 			const updatedConversation = {
@@ -246,7 +261,6 @@ const useStore = create(set => ({
 			message_type: "text",
 			sender_id: user.id,
 			timestamp: new Date().toISOString(),
-			read: false,
 			isOptimistic: true // Flag to identify optimistic messages
 		};
 
@@ -269,7 +283,8 @@ const useStore = create(set => ({
 
 		try {
 			// Send via backend API (this will also trigger WebSocket broadcast)
-			const response = await chatApiService.sendMessage(conversationId, message.content);
+			// The temporary id doubles as client_id, so a retried send is not stored twice
+			const response = await chatApiService.sendMessage(conversationId, message.content, optimisticMessage.id);
 
 			// If we get a response with message details, replace optimistic message
 			if (response && response.id) {
@@ -329,16 +344,17 @@ const useStore = create(set => ({
 
 	markConversationAsRead: async conversationId => {
 		try {
-			await chatApiService.markConversationAsRead(conversationId);
+			const receipt = await chatApiService.markConversationAsRead(conversationId);
 
 			set(state => {
 				const conversation = state.conversations[conversationId];
 				if (!conversation) return state;
 
+				const readReceipts = { ...conversation.readReceipts, [state.user?.id]: Number(receipt.last_read_seq) };
 				return {
 					conversations: {
 						...state.conversations,
-						[conversationId]: { ...conversation, unreadCount: 0 }
+						[conversationId]: { ...conversation, unreadCount: 0, readReceipts }
 					}
 				};
 			});
@@ -397,6 +413,8 @@ const useStore = create(set => ({
 			console.log("Backend returned conversation:", conversation);
 
 			// Convert backend conversation format to frontend format
+			const userId = useStore.getState().user?.id;
+			const readReceipts = toReadReceipts(conversation.read_receipts);
 			const enhancedConversation = {
 				id: conversation.id,
 				participants: conversation.participants,
@@ -408,7 +426,7 @@ const useStore = create(set => ({
 					content: msg.content,
 					type: msg.message_type,
 					timestamp: new Date(Number(msg.timestamp) / 1000000).toISOString(),
-					read: msg.read
+					seq: Number(msg.seq)
 				})),
 				lastMessage:
 					conversation.messages.length > 0
@@ -420,14 +438,17 @@ const useStore = create(set => ({
 								timestamp: new Date(
 									Number(conversation.messages[conversation.messages.length - 1].timestamp) / 1000000
 								).toISOString(),
-								read: conversation.messages[conversation.messages.length - 1].read
+								seq: Number(conversation.messages[conversation.messages.length - 1].seq)
 						  }
 						: null,
 				lastMessageTime: conversation.last_message_time
 					? new Date(Number(conversation.last_message_time) / 1000000).toISOString()
 					: null,
 				createdAt: new Date(Number(conversation.created_at) / 1000000).toISOString(),
-				unreadCount: conversation.unread_counts?.[useStore.getState().user?.id] || 0,
+				unreadCount: conversation.messages.filter(
+					msg => msg.sender_id !== userId && Number(msg.seq) > (readReceipts[userId] ?? 0)
+				).length,
+				readReceipts,
 				typingUsers: {},
 				otherUserId: conversation.participants.find(p => p !== useStore.getState().user?.id) || otherUserId
 			};
@@ -449,19 +470,8 @@ const useStore = create(set => ({
 	loadConversations: async () => {
 		try {
 			const conversations = await chatApiService.getUserConversations();
-			const conversationsMap = conversations.reduce((acc, conv) => {
-				acc[conv.id] = {
-					...conv,
-					lastMessage: conv.messages[conv.messages.length - 1] || null,
-					lastMessageTime:
-						conv.messages.length > 0
-							? new Date(Number(conv.messages[conv.messages.length - 1].timestamp) / 1000000).toISOString()
-							: null,
-					createdAt: new Date(Number(conv.created_at) / 1000000).toISOString(),
-					unreadCount: conv.unread_counts?.[useStore.getState().user?.id] || 0,
-					typingUsers: {},
-					otherUserId: conv.participants.find(p => p !== useStore.getState().user?.id) || "unknown"
-				};
+			const conversationsMap = conversations.reduce((acc, summary) => {
+				acc[summary.id] = summaryToConversation(summary, useStore.getState().user?.id);
 				return acc;
 			}, {});
 
@@ -486,7 +496,7 @@ const useStore = create(set => ({
 					content: msg.content,
 					type: msg.message_type,
 					timestamp: new Date(Number(msg.timestamp) / 1000000).toISOString(),
-					read: msg.read
+					seq: Number(msg.seq)
 				}));
 
 				const updatedConversation = {
@@ -504,9 +514,31 @@ const useStore = create(set => ({
 					}
 				};
 			});
+
+			await useStore.getState().syncReadReceipts();
 		} catch (error) {
 			console.error("Failed to refresh conversation messages:", error);
 		}
+	},
+
+	// Picks up read receipts changed since the last sync, they drive the read ticks of own messages
+	syncReadReceipts: async () => {
+		const { server_time, updates } = await chatApiService.syncConversations(useStore.getState().chatSyncedAt);
+
+		set(state => {
+			const conversations = { ...state.conversations };
+			for (const update of updates) {
+				const conversation = conversations[update.summary.id];
+				if (!conversation) continue;
+
+				conversations[update.summary.id] = {
+					...conversation,
+					unreadCount: update.summary.unread_count,
+					readReceipts: { ...conversation.readReceipts, ...toReadReceipts(update.read_receipts) }
+				};
+			}
+			return { conversations, chatSyncedAt: server_time };
+		});
 	}
 }));
 